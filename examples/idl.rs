@@ -0,0 +1,18 @@
+//! Prints the Anchor IDL of the program deployed at the address given as the first argument.
+//!
+//!     cargo run --example idl -- <PROGRAM_ID> > counter.json
+
+use learn_rust_solana_counter::idl::idl;
+use solana_program::pubkey::Pubkey;
+use std::{env, str::FromStr};
+
+fn main() {
+    let program_id = env::args()
+        .nth(1)
+        .and_then(|arg| Pubkey::from_str(&arg).ok())
+        .expect("usage: idl <PROGRAM_ID>");
+    println!(
+        "{}",
+        serde_json::to_string_pretty(&idl(&program_id)).unwrap()
+    );
+}
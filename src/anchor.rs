@@ -0,0 +1,73 @@
+//! Discriminators used by the `anchor` feature, so clients built on Anchor tooling can call the
+//! program and decode its accounts and events.
+//!
+//! Anchor derives them as the first 8 bytes of `sha256("<namespace>:<name>")`; they are written
+//! out here because they have to be known at compile time.
+
+/// Instruction name and sighash (`global:<name>`), indexed by the native one-byte tag of the
+/// same `CounterInstructions` variant.
+pub const INSTRUCTIONS: [(&str, [u8; 8]); 20] = [
+    ("increment", [11, 18, 104, 9, 104, 174, 59, 33]),
+    ("decrement", [106, 227, 168, 59, 248, 27, 150, 101]),
+    ("update", [219, 200, 88, 176, 158, 63, 253, 127]),
+    ("reset", [23, 81, 251, 84, 138, 183, 240, 214]),
+    ("initialize", [175, 175, 109, 31, 13, 152, 155, 237]),
+    ("try_decrement", [72, 55, 77, 47, 134, 102, 232, 0]),
+    ("migrate", [155, 234, 231, 146, 236, 158, 162, 30]),
+    ("close", [98, 165, 201, 177, 108, 65, 206, 96]),
+    ("set_bounds", [102, 94, 161, 128, 243, 3, 17, 26]),
+    ("compare_and_swap", [233, 146, 192, 163, 241, 3, 36, 72]),
+    ("batch", [198, 211, 248, 40, 165, 37, 21, 148]),
+    ("fan_out", [249, 206, 247, 116, 158, 244, 48, 150]),
+    ("get", [161, 224, 50, 61, 5, 210, 122, 216]),
+    ("propose_authority", [20, 148, 236, 198, 76, 119, 99, 142]),
+    ("accept_authority", [107, 86, 198, 91, 33, 12, 107, 160]),
+    ("cancel_proposal", [106, 74, 128, 146, 19, 65, 39, 23]),
+    ("initialize_multisig", [220, 130, 117, 21, 27, 227, 78, 213]),
+    ("grant_role", [218, 234, 128, 15, 82, 33, 236, 253]),
+    ("revoke_role", [179, 232, 2, 180, 48, 227, 82, 7]),
+    ("close_permissions", [191, 79, 119, 100, 71, 157, 197, 101]),
+];
+
+/// `account:CounterAccount`, stored in place of `b"counter\0"` when the feature is enabled.
+pub const ACCOUNT_DISCRIMINATOR: [u8; 8] = [164, 8, 153, 71, 8, 44, 93, 22];
+
+/// `account:Multisig`, stored in place of `b"multisig"` when the feature is enabled.
+pub const MULTISIG_DISCRIMINATOR: [u8; 8] = [224, 116, 121, 186, 68, 161, 79, 236];
+
+/// `account:Permissions`, stored in place of `b"permissn"` when the feature is enabled.
+pub const PERMISSIONS_DISCRIMINATOR: [u8; 8] = [197, 143, 173, 92, 111, 50, 127, 223];
+
+/// `event:CounterEvent`, logged in place of `b"cntevent"` when the feature is enabled.
+pub const EVENT_DISCRIMINATOR: [u8; 8] = [0, 127, 126, 199, 252, 119, 101, 222];
+
+/// Returns the native tag of the instruction whose sighash starts `input`.
+pub fn instruction_tag(input: &[u8]) -> Option<u8> {
+    let discriminator = input.get(..8)?;
+    INSTRUCTIONS
+        .iter()
+        .position(|(_, sighash)| sighash == discriminator)
+        .map(|tag| tag as u8)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use solana_program::hash::hashv;
+
+    fn sighash(namespace: &str, name: &str) -> [u8; 8] {
+        let hash = hashv(&[namespace.as_bytes(), b":", name.as_bytes()]);
+        hash.to_bytes()[..8].try_into().unwrap()
+    }
+
+    #[test]
+    fn test_discriminators() {
+        for (name, discriminator) in INSTRUCTIONS {
+            assert_eq!(sighash("global", name), discriminator, "{}", name);
+        }
+        assert_eq!(sighash("account", "CounterAccount"), ACCOUNT_DISCRIMINATOR);
+        assert_eq!(sighash("account", "Multisig"), MULTISIG_DISCRIMINATOR);
+        assert_eq!(sighash("account", "Permissions"), PERMISSIONS_DISCRIMINATOR);
+        assert_eq!(sighash("event", "CounterEvent"), EVENT_DISCRIMINATOR);
+    }
+}
@@ -0,0 +1,456 @@
+//! Builds complete counter program instructions, with the accounts in the order and with the
+//! signer/writable flags each processor expects.
+
+use crate::instructions::{
+    CompareAndSwapArgs, CounterInstructions, FanOutArgs, InitializeArgs, InitializeMultisigArgs,
+    Op, RoleArgs, UpdateArgs,
+};
+use crate::state::{Bounds, CounterValue, Role};
+use crate::{find_counter_address, find_permissions_address};
+use solana_program::{
+    bpf_loader_upgradeable,
+    instruction::{AccountMeta, Instruction},
+    pubkey::Pubkey,
+    system_program,
+};
+
+/// Creates the counter PDA derived from `payer` and `args.seed`; `payer` funds it and becomes
+/// its authority.
+pub fn initialize(program_id: &Pubkey, payer: &Pubkey, args: InitializeArgs) -> Instruction {
+    let (counter, _) = find_counter_address(program_id, payer, &args.seed);
+    Instruction::new_with_bytes(
+        *program_id,
+        &CounterInstructions::Initialize(args).pack(),
+        vec![
+            AccountMeta::new(counter, false),
+            AccountMeta::new(*payer, true),
+            AccountMeta::new_readonly(system_program::id(), false),
+        ],
+    )
+}
+
+pub fn increment(
+    program_id: &Pubkey,
+    counter: &Pubkey,
+    authority: &Pubkey,
+    value: CounterValue,
+) -> Instruction {
+    let instruction = CounterInstructions::Increment(UpdateArgs { value });
+    update_instruction(program_id, counter, authority, instruction)
+}
+
+pub fn decrement(
+    program_id: &Pubkey,
+    counter: &Pubkey,
+    authority: &Pubkey,
+    value: CounterValue,
+) -> Instruction {
+    let instruction = CounterInstructions::Decrement(UpdateArgs { value });
+    update_instruction(program_id, counter, authority, instruction)
+}
+
+pub fn update(
+    program_id: &Pubkey,
+    counter: &Pubkey,
+    authority: &Pubkey,
+    value: CounterValue,
+) -> Instruction {
+    let instruction = CounterInstructions::Update(UpdateArgs { value });
+    update_instruction(program_id, counter, authority, instruction)
+}
+
+pub fn reset(program_id: &Pubkey, counter: &Pubkey, authority: &Pubkey) -> Instruction {
+    update_instruction(program_id, counter, authority, CounterInstructions::Reset)
+}
+
+pub fn try_decrement(
+    program_id: &Pubkey,
+    counter: &Pubkey,
+    authority: &Pubkey,
+    value: CounterValue,
+) -> Instruction {
+    let instruction = CounterInstructions::TryDecrement(UpdateArgs { value });
+    update_instruction(program_id, counter, authority, instruction)
+}
+
+pub fn set_bounds(
+    program_id: &Pubkey,
+    counter: &Pubkey,
+    authority: &Pubkey,
+    bounds: Bounds,
+) -> Instruction {
+    let instruction = CounterInstructions::SetBounds(bounds);
+    update_instruction(program_id, counter, authority, instruction)
+}
+
+pub fn compare_and_swap(
+    program_id: &Pubkey,
+    counter: &Pubkey,
+    authority: &Pubkey,
+    expected: CounterValue,
+    new: CounterValue,
+) -> Instruction {
+    let instruction = CounterInstructions::CompareAndSwap(CompareAndSwapArgs { expected, new });
+    update_instruction(program_id, counter, authority, instruction)
+}
+
+pub fn batch(
+    program_id: &Pubkey,
+    counter: &Pubkey,
+    authority: &Pubkey,
+    ops: Vec<Op>,
+) -> Instruction {
+    update_instruction(
+        program_id,
+        counter,
+        authority,
+        CounterInstructions::Batch(ops),
+    )
+}
+
+/// Applies `op` to every one of `counters`, which must all share `authority`. With a multisig
+/// authority, add any permissions accounts before calling `with_multisig_signers`, which passes
+/// the members last.
+pub fn fan_out(
+    program_id: &Pubkey,
+    authority: &Pubkey,
+    counters: &[Pubkey],
+    op: Op,
+) -> Instruction {
+    let mut accounts = vec![AccountMeta::new_readonly(*authority, true)];
+    accounts.extend(
+        counters
+            .iter()
+            .map(|counter| AccountMeta::new(*counter, false)),
+    );
+    Instruction::new_with_bytes(
+        *program_id,
+        &CounterInstructions::FanOut(FanOutArgs { op, signers: 0 }).pack(),
+        accounts,
+    )
+}
+
+/// Closes the counter and sends its lamports to `destination`. Add its permissions accounts with
+/// `with_permissions_to_close`, so they are closed along with it.
+pub fn close(
+    program_id: &Pubkey,
+    counter: &Pubkey,
+    authority: &Pubkey,
+    destination: &Pubkey,
+) -> Instruction {
+    Instruction::new_with_bytes(
+        *program_id,
+        &CounterInstructions::Close.pack(),
+        vec![
+            AccountMeta::new(*counter, false),
+            AccountMeta::new_readonly(*authority, true),
+            AccountMeta::new(*destination, false),
+        ],
+    )
+}
+
+/// Upgrades a counter written with an earlier layout; `payer` covers the extra rent.
+pub fn migrate(program_id: &Pubkey, counter: &Pubkey, payer: &Pubkey) -> Instruction {
+    Instruction::new_with_bytes(
+        *program_id,
+        &CounterInstructions::Migrate.pack(),
+        vec![
+            AccountMeta::new(*counter, false),
+            AccountMeta::new(*payer, true),
+            AccountMeta::new_readonly(system_program::id(), false),
+        ],
+    )
+}
+
+/// Upgrades a legacy 4-byte counter, which has no authority yet. `upgrade_authority` must be
+/// the upgrade authority of the program and becomes the counter authority.
+pub fn migrate_legacy(
+    program_id: &Pubkey,
+    counter: &Pubkey,
+    payer: &Pubkey,
+    upgrade_authority: &Pubkey,
+) -> Instruction {
+    let (program_data, _) =
+        Pubkey::find_program_address(&[program_id.as_ref()], &bpf_loader_upgradeable::id());
+    let mut instruction = migrate(program_id, counter, payer);
+    instruction.accounts.extend([
+        AccountMeta::new_readonly(program_data, false),
+        AccountMeta::new_readonly(*upgrade_authority, true),
+    ]);
+    instruction
+}
+
+pub fn get(program_id: &Pubkey, counter: &Pubkey) -> Instruction {
+    Instruction::new_with_bytes(
+        *program_id,
+        &CounterInstructions::Get.pack(),
+        vec![AccountMeta::new_readonly(*counter, false)],
+    )
+}
+
+/// Proposes `new_authority`; `authority` stays in control until it is accepted.
+pub fn propose_authority(
+    program_id: &Pubkey,
+    counter: &Pubkey,
+    authority: &Pubkey,
+    new_authority: &Pubkey,
+) -> Instruction {
+    let instruction = CounterInstructions::ProposeAuthority(*new_authority);
+    update_instruction(program_id, counter, authority, instruction)
+}
+
+/// Signed by the proposed authority to take over the counter.
+pub fn accept_authority(
+    program_id: &Pubkey,
+    counter: &Pubkey,
+    new_authority: &Pubkey,
+) -> Instruction {
+    update_instruction(
+        program_id,
+        counter,
+        new_authority,
+        CounterInstructions::AcceptAuthority,
+    )
+}
+
+pub fn cancel_proposal(program_id: &Pubkey, counter: &Pubkey, authority: &Pubkey) -> Instruction {
+    update_instruction(
+        program_id,
+        counter,
+        authority,
+        CounterInstructions::CancelProposal,
+    )
+}
+
+/// Sets up a multisig in `multisig`, an account of `Multisig::LEN` bytes that has to be created
+/// for the program earlier in the same transaction.
+pub fn initialize_multisig(
+    program_id: &Pubkey,
+    multisig: &Pubkey,
+    threshold: u8,
+    signers: Vec<Pubkey>,
+) -> Instruction {
+    Instruction::new_with_bytes(
+        *program_id,
+        &CounterInstructions::InitializeMultisig(InitializeMultisigArgs { threshold, signers })
+            .pack(),
+        vec![AccountMeta::new(*multisig, false)],
+    )
+}
+
+/// Turns an instruction built with `multisig` as the authority into one signed by its
+/// `signers` instead. For `fan_out` the signer count in the instruction data is updated too.
+pub fn with_multisig_signers(
+    mut instruction: Instruction,
+    multisig: &Pubkey,
+    signers: &[Pubkey],
+) -> Instruction {
+    for account in &mut instruction.accounts {
+        if account.pubkey == *multisig {
+            account.is_signer = false;
+        }
+    }
+    if let Ok(CounterInstructions::FanOut(mut args)) =
+        CounterInstructions::unpack(&instruction.data)
+    {
+        args.signers += signers.len() as u8;
+        instruction.data = CounterInstructions::FanOut(args).pack();
+    }
+    instruction.accounts.extend(
+        signers
+            .iter()
+            .map(|signer| AccountMeta::new_readonly(*signer, true)),
+    );
+    instruction
+}
+
+/// Gives `member` `role` on the counter; `payer` funds the permissions account on the first
+/// grant.
+pub fn grant_role(
+    program_id: &Pubkey,
+    counter: &Pubkey,
+    authority: &Pubkey,
+    payer: &Pubkey,
+    member: &Pubkey,
+    role: Role,
+) -> Instruction {
+    let (permissions, _) = find_permissions_address(program_id, counter, member);
+    Instruction::new_with_bytes(
+        *program_id,
+        &CounterInstructions::GrantRole(RoleArgs {
+            member: *member,
+            role,
+        })
+        .pack(),
+        vec![
+            AccountMeta::new_readonly(*counter, false),
+            AccountMeta::new_readonly(*authority, true),
+            AccountMeta::new(permissions, false),
+            AccountMeta::new(*payer, true),
+            AccountMeta::new_readonly(system_program::id(), false),
+        ],
+    )
+}
+
+pub fn revoke_role(
+    program_id: &Pubkey,
+    counter: &Pubkey,
+    authority: &Pubkey,
+    member: &Pubkey,
+    role: Role,
+) -> Instruction {
+    let (permissions, _) = find_permissions_address(program_id, counter, member);
+    Instruction::new_with_bytes(
+        *program_id,
+        &CounterInstructions::RevokeRole(RoleArgs {
+            member: *member,
+            role,
+        })
+        .pack(),
+        vec![
+            AccountMeta::new_readonly(*counter, false),
+            AccountMeta::new_readonly(*authority, true),
+            AccountMeta::new(permissions, false),
+        ],
+    )
+}
+
+/// Passes the permissions account of `member` on `counter`, for an instruction built with
+/// `member` in place of the counter authority. Call it once per counter for `fan_out`.
+pub fn with_permissions(
+    mut instruction: Instruction,
+    counter: &Pubkey,
+    member: &Pubkey,
+) -> Instruction {
+    let (permissions, _) = find_permissions_address(&instruction.program_id, counter, member);
+    instruction
+        .accounts
+        .push(AccountMeta::new_readonly(permissions, false));
+    instruction
+}
+
+/// Passes the permissions account of `member` on `counter` to a `close` instruction, so it is
+/// closed as well.
+pub fn with_permissions_to_close(
+    mut instruction: Instruction,
+    counter: &Pubkey,
+    member: &Pubkey,
+) -> Instruction {
+    let (permissions, _) = find_permissions_address(&instruction.program_id, counter, member);
+    instruction
+        .accounts
+        .push(AccountMeta::new(permissions, false));
+    instruction
+}
+
+/// Closes the permissions account of `member` and sends its lamports to `destination`.
+/// `authority` is the counter authority or `member` itself.
+pub fn close_permissions(
+    program_id: &Pubkey,
+    counter: &Pubkey,
+    authority: &Pubkey,
+    member: &Pubkey,
+    destination: &Pubkey,
+) -> Instruction {
+    let (permissions, _) = find_permissions_address(program_id, counter, member);
+    Instruction::new_with_bytes(
+        *program_id,
+        &CounterInstructions::ClosePermissions.pack(),
+        vec![
+            AccountMeta::new_readonly(*counter, false),
+            AccountMeta::new_readonly(*authority, true),
+            AccountMeta::new(permissions, false),
+            AccountMeta::new(*destination, false),
+        ],
+    )
+}
+
+/// The accounts shared by every instruction handled by `process_update`.
+fn update_instruction(
+    program_id: &Pubkey,
+    counter: &Pubkey,
+    authority: &Pubkey,
+    instruction: CounterInstructions,
+) -> Instruction {
+    Instruction::new_with_bytes(
+        *program_id,
+        &instruction.pack(),
+        vec![
+            AccountMeta::new(*counter, false),
+            AccountMeta::new_readonly(*authority, true),
+        ],
+    )
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_account_flags() {
+        let program_id = Pubkey::new_unique();
+        let counter = Pubkey::new_unique();
+        let authority = Pubkey::new_unique();
+        let destination = Pubkey::new_unique();
+
+        let instruction = increment(&program_id, &counter, &authority, CounterValue::U32(1));
+        assert_eq!(instruction.program_id, program_id);
+        assert_eq!(
+            instruction.accounts,
+            vec![
+                AccountMeta::new(counter, false),
+                AccountMeta::new_readonly(authority, true),
+            ]
+        );
+        assert_eq!(
+            CounterInstructions::unpack(&instruction.data),
+            Ok(CounterInstructions::Increment(UpdateArgs {
+                value: CounterValue::U32(1)
+            }))
+        );
+
+        let instruction = close(&program_id, &counter, &authority, &destination);
+        assert_eq!(
+            instruction.accounts,
+            vec![
+                AccountMeta::new(counter, false),
+                AccountMeta::new_readonly(authority, true),
+                AccountMeta::new(destination, false),
+            ]
+        );
+
+        let other = Pubkey::new_unique();
+        let instruction = fan_out(&program_id, &authority, &[counter, other], Op::Reset);
+        assert_eq!(
+            instruction.accounts,
+            vec![
+                AccountMeta::new_readonly(authority, true),
+                AccountMeta::new(counter, false),
+                AccountMeta::new(other, false),
+            ]
+        );
+        let member = Pubkey::new_unique();
+        let instruction = with_multisig_signers(instruction, &authority, &[member]);
+        assert_eq!(
+            instruction.accounts[0],
+            AccountMeta::new_readonly(authority, false)
+        );
+        assert_eq!(
+            instruction.accounts[3],
+            AccountMeta::new_readonly(member, true)
+        );
+        assert_eq!(
+            CounterInstructions::unpack(&instruction.data),
+            Ok(CounterInstructions::FanOut(FanOutArgs {
+                op: Op::Reset,
+                signers: 1,
+            }))
+        );
+
+        let instruction = get(&program_id, &counter);
+        assert_eq!(
+            instruction.accounts,
+            vec![AccountMeta::new_readonly(counter, false)]
+        );
+    }
+}
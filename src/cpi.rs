@@ -0,0 +1,169 @@
+//! Helpers for other on-chain programs that call the counter program through CPI.
+//!
+//! Every function takes the counter program's `AccountInfo` so its id is known, and the
+//! `signer_seeds` to pass to `invoke_signed` when the authority is a PDA of the caller. Use
+//! `&[]` when the authority signed the outer transaction.
+
+use crate::builder;
+use crate::get_counter_return_data;
+use crate::state::{Bounds, CounterAccount, CounterValue};
+use solana_program::{
+    account_info::AccountInfo, entrypoint::ProgramResult, instruction::Instruction,
+    program::invoke_signed, program_error::ProgramError, pubkey::Pubkey,
+};
+
+pub fn increment<'a>(
+    program: &AccountInfo<'a>,
+    counter: &AccountInfo<'a>,
+    authority: &AccountInfo<'a>,
+    value: CounterValue,
+    signer_seeds: &[&[&[u8]]],
+) -> ProgramResult {
+    let instruction = builder::increment(program.key, counter.key, authority.key, value);
+    invoke_update(program, counter, authority, instruction, signer_seeds)
+}
+
+pub fn decrement<'a>(
+    program: &AccountInfo<'a>,
+    counter: &AccountInfo<'a>,
+    authority: &AccountInfo<'a>,
+    value: CounterValue,
+    signer_seeds: &[&[&[u8]]],
+) -> ProgramResult {
+    let instruction = builder::decrement(program.key, counter.key, authority.key, value);
+    invoke_update(program, counter, authority, instruction, signer_seeds)
+}
+
+pub fn update<'a>(
+    program: &AccountInfo<'a>,
+    counter: &AccountInfo<'a>,
+    authority: &AccountInfo<'a>,
+    value: CounterValue,
+    signer_seeds: &[&[&[u8]]],
+) -> ProgramResult {
+    let instruction = builder::update(program.key, counter.key, authority.key, value);
+    invoke_update(program, counter, authority, instruction, signer_seeds)
+}
+
+pub fn reset<'a>(
+    program: &AccountInfo<'a>,
+    counter: &AccountInfo<'a>,
+    authority: &AccountInfo<'a>,
+    signer_seeds: &[&[&[u8]]],
+) -> ProgramResult {
+    let instruction = builder::reset(program.key, counter.key, authority.key);
+    invoke_update(program, counter, authority, instruction, signer_seeds)
+}
+
+pub fn try_decrement<'a>(
+    program: &AccountInfo<'a>,
+    counter: &AccountInfo<'a>,
+    authority: &AccountInfo<'a>,
+    value: CounterValue,
+    signer_seeds: &[&[&[u8]]],
+) -> ProgramResult {
+    let instruction = builder::try_decrement(program.key, counter.key, authority.key, value);
+    invoke_update(program, counter, authority, instruction, signer_seeds)
+}
+
+pub fn set_bounds<'a>(
+    program: &AccountInfo<'a>,
+    counter: &AccountInfo<'a>,
+    authority: &AccountInfo<'a>,
+    bounds: Bounds,
+    signer_seeds: &[&[&[u8]]],
+) -> ProgramResult {
+    let instruction = builder::set_bounds(program.key, counter.key, authority.key, bounds);
+    invoke_update(program, counter, authority, instruction, signer_seeds)
+}
+
+pub fn compare_and_swap<'a>(
+    program: &AccountInfo<'a>,
+    counter: &AccountInfo<'a>,
+    authority: &AccountInfo<'a>,
+    expected: CounterValue,
+    new: CounterValue,
+    signer_seeds: &[&[&[u8]]],
+) -> ProgramResult {
+    let instruction =
+        builder::compare_and_swap(program.key, counter.key, authority.key, expected, new);
+    invoke_update(program, counter, authority, instruction, signer_seeds)
+}
+
+pub fn propose_authority<'a>(
+    program: &AccountInfo<'a>,
+    counter: &AccountInfo<'a>,
+    authority: &AccountInfo<'a>,
+    new_authority: &Pubkey,
+    signer_seeds: &[&[&[u8]]],
+) -> ProgramResult {
+    let instruction =
+        builder::propose_authority(program.key, counter.key, authority.key, new_authority);
+    invoke_update(program, counter, authority, instruction, signer_seeds)
+}
+
+/// Takes over the counter as its pending authority.
+pub fn accept_authority<'a>(
+    program: &AccountInfo<'a>,
+    counter: &AccountInfo<'a>,
+    new_authority: &AccountInfo<'a>,
+    signer_seeds: &[&[&[u8]]],
+) -> ProgramResult {
+    let instruction = builder::accept_authority(program.key, counter.key, new_authority.key);
+    invoke_update(program, counter, new_authority, instruction, signer_seeds)
+}
+
+pub fn cancel_proposal<'a>(
+    program: &AccountInfo<'a>,
+    counter: &AccountInfo<'a>,
+    authority: &AccountInfo<'a>,
+    signer_seeds: &[&[&[u8]]],
+) -> ProgramResult {
+    let instruction = builder::cancel_proposal(program.key, counter.key, authority.key);
+    invoke_update(program, counter, authority, instruction, signer_seeds)
+}
+
+/// Closes the counter and sends its lamports to `destination`.
+pub fn close<'a>(
+    program: &AccountInfo<'a>,
+    counter: &AccountInfo<'a>,
+    authority: &AccountInfo<'a>,
+    destination: &AccountInfo<'a>,
+    signer_seeds: &[&[&[u8]]],
+) -> ProgramResult {
+    let instruction = builder::close(program.key, counter.key, authority.key, destination.key);
+    invoke_signed(
+        &instruction,
+        &[
+            counter.clone(),
+            authority.clone(),
+            destination.clone(),
+            program.clone(),
+        ],
+        signer_seeds,
+    )
+}
+
+/// Reads the counter through the `Get` instruction.
+pub fn get<'a>(
+    program: &AccountInfo<'a>,
+    counter: &AccountInfo<'a>,
+) -> Result<CounterAccount, ProgramError> {
+    let instruction = builder::get(program.key, counter.key);
+    invoke_signed(&instruction, &[counter.clone(), program.clone()], &[])?;
+    get_counter_return_data(program.key)
+}
+
+fn invoke_update<'a>(
+    program: &AccountInfo<'a>,
+    counter: &AccountInfo<'a>,
+    authority: &AccountInfo<'a>,
+    instruction: Instruction,
+    signer_seeds: &[&[&[u8]]],
+) -> ProgramResult {
+    invoke_signed(
+        &instruction,
+        &[counter.clone(), authority.clone(), program.clone()],
+        signer_seeds,
+    )
+}
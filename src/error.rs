@@ -0,0 +1,116 @@
+use num_derive::FromPrimitive;
+use solana_program::{
+    decode_error::DecodeError,
+    msg,
+    program_error::{PrintProgramError, ProgramError},
+};
+use thiserror::Error;
+
+/// Errors returned by the counter program, surfaced as `ProgramError::Custom(code)`.
+///
+/// The discriminants are the custom error codes seen by clients, so variants must
+/// only ever be appended.
+#[derive(Clone, Copy, Debug, Eq, Error, FromPrimitive, PartialEq)]
+pub enum CounterError {
+    /// The signer does not match the authority stored in the counter account
+    #[error("Signer is not the counter authority")]
+    Unauthorized = 0,
+    /// The first byte of the instruction data is not a known instruction
+    #[error("Unknown instruction tag")]
+    InvalidInstructionTag = 1,
+    /// The instruction data ended before all arguments were read
+    #[error("Instruction arguments are truncated")]
+    TruncatedInstructionData = 2,
+    /// Bytes were left over after the instruction arguments were read
+    #[error("Instruction data has trailing bytes")]
+    TrailingInstructionData = 3,
+    /// The instruction arguments could not be decoded
+    #[error("Instruction arguments are malformed")]
+    MalformedInstructionData = 4,
+    /// The counter value does not fit in its integer type
+    #[error("Counter arithmetic overflowed")]
+    Overflow = 5,
+    /// The counter would drop below the minimum of its kind, 0 or `i64::MIN`; dropping below
+    /// the lower bound fails with `OutOfBounds` instead
+    #[error("Counter arithmetic underflowed")]
+    Underflow = 6,
+    /// The account data does not start with the counter discriminator
+    #[error("Account is not a counter account")]
+    InvalidAccountDiscriminator = 7,
+    /// The account header carries a layout version this program does not understand
+    #[error("Unsupported counter account version")]
+    UnsupportedAccountVersion = 8,
+    /// The account still uses the 4-byte layout and has to be migrated first
+    #[error("Counter account uses the legacy layout, run Migrate first")]
+    LegacyAccount = 9,
+    /// An instruction value is of a different kind than the counter
+    #[error("Value kind does not match the counter kind")]
+    KindMismatch = 10,
+    /// Increment and decrement amounts must not be negative
+    #[error("Amount must not be negative")]
+    NegativeAmount = 11,
+    /// The counter account has been closed
+    #[error("Counter account is closed")]
+    AccountClosed = 12,
+    /// The counter account is not owned by this program
+    #[error("Counter account is not owned by the counter program")]
+    IncorrectAccountOwner = 13,
+    /// The counter account was not passed as writable
+    #[error("Counter account is not writable")]
+    AccountNotWritable = 14,
+    /// The counter account data is not the size of the current layout
+    #[error("Counter account has an unexpected data length")]
+    InvalidAccountDataLength = 15,
+    /// The counter account does not hold enough lamports to be rent exempt
+    #[error("Counter account is not rent exempt")]
+    AccountNotRentExempt = 16,
+    /// The operation would move the counter outside its configured bounds
+    #[error("Counter value is out of bounds")]
+    OutOfBounds = 17,
+    /// The configured minimum is larger than the maximum
+    #[error("Counter bounds are invalid")]
+    InvalidBounds = 18,
+    /// `CompareAndSwap` found a different value than expected
+    #[error("Counter value does not match the expected value")]
+    CompareMismatch = 19,
+    /// No counter was returned by the counter program
+    #[error("Counter program did not return a counter")]
+    MissingReturnData = 20,
+    /// The instruction creates or deletes the counter and has no state transition to apply
+    #[error("Instruction cannot be applied to an existing counter")]
+    NotApplicable = 21,
+    /// `AcceptAuthority` or `CancelProposal` was sent with no authority proposed
+    #[error("Counter has no pending authority")]
+    NoPendingAuthority = 22,
+    /// The multisig threshold is out of range or its signers are repeated
+    #[error("Multisig threshold or signers are invalid")]
+    InvalidMultisig = 23,
+    /// Fewer multisig members signed than its threshold requires
+    #[error("Not enough multisig signers")]
+    NotEnoughSigners = 24,
+}
+
+impl From<CounterError> for ProgramError {
+    fn from(e: CounterError) -> Self {
+        ProgramError::Custom(e as u32)
+    }
+}
+
+impl<T> DecodeError<T> for CounterError {
+    fn type_of() -> &'static str {
+        "CounterError"
+    }
+}
+
+impl PrintProgramError for CounterError {
+    fn print<E>(&self)
+    where
+        E: 'static
+            + std::error::Error
+            + DecodeError<E>
+            + PrintProgramError
+            + num_traits::FromPrimitive,
+    {
+        msg!("Counter error: {}", self);
+    }
+}
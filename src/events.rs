@@ -0,0 +1,144 @@
+use crate::state::{CounterValue, Role};
+use borsh::{BorshDeserialize, BorshSerialize};
+use solana_program::{log::sol_log_data, pubkey::Pubkey};
+
+/// First bytes of every event payload, so indexers can tell counter events from other
+/// `Program data:` lines.
+#[cfg(not(feature = "anchor"))]
+pub const EVENT_DISCRIMINATOR: [u8; 8] = *b"cntevent";
+
+/// First bytes of every event payload, matching what Anchor's `emit!` logs for `CounterEvent`.
+#[cfg(feature = "anchor")]
+pub const EVENT_DISCRIMINATOR: [u8; 8] = crate::anchor::EVENT_DISCRIMINATOR;
+
+/// The operation that changed a counter.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, BorshDeserialize, BorshSerialize)]
+pub enum Operation {
+    Initialize,
+    Increment,
+    Decrement,
+    TryDecrement,
+    Update,
+    Reset,
+    CompareAndSwap,
+    SetBounds,
+    Migrate,
+    Close,
+    ProposeAuthority,
+    AcceptAuthority,
+    CancelProposal,
+    GrantRole,
+    RevokeRole,
+    ClosePermissions,
+}
+
+/// Emitted through `sol_log_data` for every state change of a counter.
+#[derive(Clone, Debug, PartialEq, Eq, BorshDeserialize, BorshSerialize)]
+pub struct CounterEvent {
+    pub counter: Pubkey,
+    pub operation: Operation,
+    /// Value before the operation; `None` when the counter was just created.
+    pub old: Option<CounterValue>,
+    /// Value after the operation; `None` when the counter was closed.
+    pub new: Option<CounterValue>,
+    /// Signer that performed the operation.
+    pub actor: Pubkey,
+    pub slot: u64,
+    /// Member whose roles changed, for `GrantRole`, `RevokeRole` and `ClosePermissions`.
+    pub member: Option<Pubkey>,
+    /// Role granted or revoked; `None` when all roles of the member were closed.
+    pub role: Option<Role>,
+}
+
+impl CounterEvent {
+    /// Encodes the event as `EVENT_DISCRIMINATOR` followed by its borsh encoding.
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut data = EVENT_DISCRIMINATOR.to_vec();
+        // Writing into a `Vec` cannot fail.
+        self.serialize(&mut data).unwrap();
+        data
+    }
+
+    /// Decodes an event written by `to_bytes`, or `None` if `data` is not a counter event.
+    pub fn from_bytes(data: &[u8]) -> Option<Self> {
+        let payload = data.strip_prefix(&EVENT_DISCRIMINATOR)?;
+        Self::try_from_slice(payload).ok()
+    }
+
+    pub fn emit(&self) {
+        sol_log_data(&[&self.to_bytes()]);
+    }
+}
+
+/// Collects the events emitted by `program_id` from the log messages of a transaction.
+///
+/// `Program data:` lines are attributed to the program on top of the invocation stack, so
+/// events logged by other programs with the same layout are ignored.
+#[cfg(not(target_os = "solana"))]
+pub fn parse_logs<S: AsRef<str>>(program_id: &Pubkey, logs: &[S]) -> Vec<CounterEvent> {
+    use base64::{engine::general_purpose::STANDARD, Engine};
+
+    let program = program_id.to_string();
+    let mut stack: Vec<&str> = vec![];
+    let mut events = vec![];
+    for log in logs {
+        let log = log.as_ref();
+        if let Some(data) = log.strip_prefix("Program data: ") {
+            if stack.last() != Some(&program.as_str()) {
+                continue;
+            }
+            events.extend(
+                data.split(' ')
+                    .filter_map(|field| STANDARD.decode(field).ok())
+                    .filter_map(|bytes| CounterEvent::from_bytes(&bytes)),
+            );
+        } else if let Some(rest) = log.strip_prefix("Program ") {
+            let mut words = rest.split(' ');
+            match (words.next(), words.next()) {
+                (Some(id), Some("invoke")) => stack.push(id),
+                (Some(_), Some("success")) | (Some(_), Some("failed:")) => {
+                    stack.pop();
+                }
+                _ => {}
+            }
+        }
+    }
+    events
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use base64::{engine::general_purpose::STANDARD, Engine};
+
+    #[test]
+    fn test_parse_logs() {
+        let program_id = Pubkey::new_unique();
+        let other_program = Pubkey::new_unique();
+        let event = CounterEvent {
+            counter: Pubkey::new_unique(),
+            operation: Operation::Increment,
+            old: Some(CounterValue::U64(1)),
+            new: Some(CounterValue::U64(3)),
+            actor: Pubkey::new_unique(),
+            slot: 42,
+            member: None,
+            role: None,
+        };
+        let data = format!("Program data: {}", STANDARD.encode(event.to_bytes()));
+        let logs = vec![
+            format!("Program {} invoke [1]", other_program),
+            format!("Program {} invoke [2]", program_id),
+            "Program log: Counter program entry point".to_string(),
+            data.clone(),
+            format!("Program {} success", program_id),
+            // Same bytes logged by the caller must not be attributed to the counter program.
+            data,
+            format!("Program {} success", other_program),
+        ];
+
+        assert_eq!(parse_logs(&program_id, &logs), vec![event]);
+        assert_eq!(parse_logs(&other_program, &logs).len(), 1);
+        assert_eq!(CounterEvent::from_bytes(b"cntevent"), None);
+    }
+}
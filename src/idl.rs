@@ -0,0 +1,409 @@
+//! Anchor-format IDL of the program, for clients built with the `anchor` feature in mind.
+//!
+//! The layout follows the Anchor 0.30 IDL spec. The discriminators come from the `anchor`
+//! module, so the IDL only matches a build of the program with that feature enabled.
+
+use crate::anchor;
+use crate::error::CounterError;
+use crate::state::MAX_SIGNERS;
+use crate::PERMISSIONS_SEED;
+use num_traits::FromPrimitive;
+use serde_json::{json, Value};
+use solana_program::{pubkey::Pubkey, system_program};
+
+/// Builds the IDL of the program deployed at `program_id`.
+pub fn idl(program_id: &Pubkey) -> Value {
+    json!({
+        "address": program_id.to_string(),
+        "metadata": {
+            "name": env!("CARGO_PKG_NAME").replace('-', "_"),
+            "version": env!("CARGO_PKG_VERSION"),
+            "spec": "0.1.0",
+        },
+        "instructions": instructions(),
+        "accounts": [
+            {
+                "name": "CounterAccount",
+                "discriminator": anchor::ACCOUNT_DISCRIMINATOR,
+            },
+            {
+                "name": "Multisig",
+                "discriminator": anchor::MULTISIG_DISCRIMINATOR,
+            },
+            {
+                "name": "Permissions",
+                "discriminator": anchor::PERMISSIONS_DISCRIMINATOR,
+            },
+        ],
+        "events": [{
+            "name": "CounterEvent",
+            "discriminator": anchor::EVENT_DISCRIMINATOR,
+        }],
+        "errors": errors(),
+        "types": types(),
+    })
+}
+
+fn instructions() -> Vec<Value> {
+    let counter = json!({ "name": "counter", "writable": true });
+    let authority = json!({ "name": "authority", "signer": true });
+    let payer = json!({ "name": "payer", "writable": true, "signer": true });
+    let system_program = json!({
+        "name": "system_program",
+        "address": system_program::id().to_string(),
+    });
+    let value = [arg("value", defined("CounterValue"))];
+    let role_args = vec![arg("member", json!("pubkey")), arg("role", defined("Role"))];
+    let permissions = json!({
+        "name": "permissions",
+        "writable": true,
+        "pda": {
+            "seeds": [
+                { "kind": "const", "value": PERMISSIONS_SEED },
+                { "kind": "account", "path": "counter" },
+                { "kind": "arg", "path": "member" },
+            ],
+        },
+    });
+    let role_counter = json!({ "name": "counter" });
+
+    anchor::INSTRUCTIONS
+        .iter()
+        .map(|&(name, discriminator)| {
+            let (accounts, args) = match name {
+                "increment" | "decrement" | "update" | "try_decrement" => {
+                    (vec![counter.clone(), authority.clone()], value.to_vec())
+                }
+                "reset" => (vec![counter.clone(), authority.clone()], vec![]),
+                "initialize" => (
+                    vec![counter.clone(), payer.clone(), system_program.clone()],
+                    vec![
+                        arg("seed", json!("bytes")),
+                        arg("kind", defined("CounterKind")),
+                        arg("overflow_policy", defined("OverflowPolicy")),
+                        arg("underflow_policy", defined("UnderflowPolicy")),
+                        arg("bounds", defined("Bounds")),
+                    ],
+                ),
+                // The last two are only passed for legacy counters.
+                "migrate" => (
+                    vec![
+                        counter.clone(),
+                        payer.clone(),
+                        system_program.clone(),
+                        json!({ "name": "program_data", "optional": true }),
+                        json!({ "name": "upgrade_authority", "signer": true, "optional": true }),
+                    ],
+                    vec![],
+                ),
+                "close" => (
+                    vec![
+                        counter.clone(),
+                        authority.clone(),
+                        json!({ "name": "destination", "writable": true }),
+                    ],
+                    vec![],
+                ),
+                "set_bounds" => (
+                    vec![counter.clone(), authority.clone()],
+                    vec![arg("bounds", defined("Bounds"))],
+                ),
+                "compare_and_swap" => (
+                    vec![counter.clone(), authority.clone()],
+                    vec![
+                        arg("expected", defined("CounterValue")),
+                        arg("new", defined("CounterValue")),
+                    ],
+                ),
+                "batch" => (
+                    vec![counter.clone(), authority.clone()],
+                    vec![arg("ops", json!({ "vec": defined("Op") }))],
+                ),
+                // The counters follow the authority as remaining accounts.
+                "fan_out" => (
+                    vec![authority.clone()],
+                    vec![arg("op", defined("Op")), arg("signers", json!("u8"))],
+                ),
+                "get" => (vec![json!({ "name": "counter" })], vec![]),
+                "propose_authority" => (
+                    vec![counter.clone(), authority.clone()],
+                    vec![arg("new_authority", json!("pubkey"))],
+                ),
+                "accept_authority" => (
+                    vec![
+                        counter.clone(),
+                        json!({ "name": "new_authority", "signer": true }),
+                    ],
+                    vec![],
+                ),
+                "cancel_proposal" => (vec![counter.clone(), authority.clone()], vec![]),
+                "initialize_multisig" => (
+                    vec![json!({ "name": "multisig", "writable": true })],
+                    vec![
+                        arg("threshold", json!("u8")),
+                        arg("signers", json!({ "vec": "pubkey" })),
+                    ],
+                ),
+                "grant_role" => (
+                    vec![
+                        role_counter.clone(),
+                        authority.clone(),
+                        permissions.clone(),
+                        payer.clone(),
+                        system_program.clone(),
+                    ],
+                    role_args.clone(),
+                ),
+                "revoke_role" => (
+                    vec![role_counter.clone(), authority.clone(), permissions.clone()],
+                    role_args.clone(),
+                ),
+                "close_permissions" => (
+                    vec![
+                        role_counter.clone(),
+                        authority.clone(),
+                        json!({ "name": "permissions", "writable": true }),
+                        json!({ "name": "destination", "writable": true }),
+                    ],
+                    vec![],
+                ),
+                _ => unreachable!("instruction {} has no IDL entry", name),
+            };
+            json!({
+                "name": name,
+                "discriminator": discriminator,
+                "accounts": accounts,
+                "args": args,
+            })
+        })
+        .collect()
+}
+
+fn errors() -> Vec<Value> {
+    (0..)
+        .map_while(CounterError::from_u32)
+        .map(|error| {
+            json!({
+                "code": error as u32,
+                "name": format!("{:?}", error),
+                "msg": error.to_string(),
+            })
+        })
+        .collect()
+}
+
+fn types() -> Vec<Value> {
+    let counter_value = defined("CounterValue");
+    let optional_value = json!({ "option": counter_value });
+    vec![
+        enum_type("CounterKind", vec![unit("U32"), unit("U64"), unit("I64")]),
+        enum_type(
+            "CounterValue",
+            vec![
+                tuple("U32", json!("u32")),
+                tuple("U64", json!("u64")),
+                tuple("I64", json!("i64")),
+            ],
+        ),
+        enum_type(
+            "OverflowPolicy",
+            vec![unit("Error"), unit("Saturate"), unit("Wrap")],
+        ),
+        enum_type("UnderflowPolicy", vec![unit("Saturate"), unit("Error")]),
+        enum_type("BoundsPolicy", vec![unit("Reject"), unit("Clamp")]),
+        struct_type(
+            "Bounds",
+            vec![
+                arg("min", optional_value.clone()),
+                arg("max", optional_value.clone()),
+                arg("policy", defined("BoundsPolicy")),
+            ],
+        ),
+        struct_type(
+            "CompareAndSwapArgs",
+            vec![
+                arg("expected", counter_value.clone()),
+                arg("new", counter_value.clone()),
+            ],
+        ),
+        enum_type(
+            "Op",
+            vec![
+                tuple("Increment", counter_value.clone()),
+                tuple("Decrement", counter_value.clone()),
+                tuple("Update", counter_value.clone()),
+                unit("Reset"),
+                tuple("TryDecrement", counter_value.clone()),
+                tuple("CompareAndSwap", defined("CompareAndSwapArgs")),
+            ],
+        ),
+        // Stored as a `CounterData`, minus the discriminator. Values are the little-endian
+        // bits of a `CounterValue` of `kind`.
+        json!({
+            "name": "CounterAccount",
+            "serialization": "bytemuck",
+            "repr": { "kind": "c" },
+            "type": {
+                "kind": "struct",
+                "fields": [
+                    arg("version", json!("u8")),
+                    arg("kind", json!("u8")),
+                    arg("overflow_policy", json!("u8")),
+                    arg("underflow_policy", json!("u8")),
+                    arg("bounds_policy", json!("u8")),
+                    arg("has_min", json!("u8")),
+                    arg("has_max", json!("u8")),
+                    arg("has_pending_authority", json!("u8")),
+                    arg("authority", json!("pubkey")),
+                    arg("counter", json!("u64")),
+                    arg("min", json!("u64")),
+                    arg("max", json!("u64")),
+                    arg("pending_authority", json!("pubkey")),
+                    arg("nonce", json!("u64")),
+                ],
+            },
+        }),
+        // Only the first `len` signers are members.
+        json!({
+            "name": "Multisig",
+            "serialization": "bytemuck",
+            "repr": { "kind": "c" },
+            "type": {
+                "kind": "struct",
+                "fields": [
+                    arg("threshold", json!("u8")),
+                    arg("len", json!("u8")),
+                    arg("signers", json!({ "array": ["pubkey", MAX_SIGNERS] })),
+                ],
+            },
+        }),
+        // Only the bits of the roles granted are set, `1 << role` for each `Role`.
+        json!({
+            "name": "Permissions",
+            "serialization": "bytemuck",
+            "repr": { "kind": "c" },
+            "type": {
+                "kind": "struct",
+                "fields": [
+                    arg("counter", json!("pubkey")),
+                    arg("member", json!("pubkey")),
+                    arg("nonce", json!("u64")),
+                    arg("roles", json!("u8")),
+                ],
+            },
+        }),
+        enum_type(
+            "Role",
+            vec![
+                unit("Incrementer"),
+                unit("Decrementer"),
+                unit("Updater"),
+                unit("Resetter"),
+                unit("Admin"),
+            ],
+        ),
+        enum_type(
+            "Operation",
+            [
+                "Initialize",
+                "Increment",
+                "Decrement",
+                "TryDecrement",
+                "Update",
+                "Reset",
+                "CompareAndSwap",
+                "SetBounds",
+                "Migrate",
+                "Close",
+                "ProposeAuthority",
+                "AcceptAuthority",
+                "CancelProposal",
+                "GrantRole",
+                "RevokeRole",
+                "ClosePermissions",
+            ]
+            .into_iter()
+            .map(unit)
+            .collect(),
+        ),
+        struct_type(
+            "CounterEvent",
+            vec![
+                arg("counter", json!("pubkey")),
+                arg("operation", defined("Operation")),
+                arg("old", optional_value.clone()),
+                arg("new", optional_value),
+                arg("actor", json!("pubkey")),
+                arg("slot", json!("u64")),
+                arg("member", json!({ "option": "pubkey" })),
+                arg("role", json!({ "option": defined("Role") })),
+            ],
+        ),
+    ]
+}
+
+fn defined(name: &str) -> Value {
+    json!({ "defined": { "name": name } })
+}
+
+fn arg(name: &str, ty: Value) -> Value {
+    json!({ "name": name, "type": ty })
+}
+
+fn unit(name: &str) -> Value {
+    json!({ "name": name })
+}
+
+fn tuple(name: &str, field: Value) -> Value {
+    json!({ "name": name, "fields": [field] })
+}
+
+fn enum_type(name: &str, variants: Vec<Value>) -> Value {
+    json!({ "name": name, "type": { "kind": "enum", "variants": variants } })
+}
+
+fn struct_type(name: &str, fields: Vec<Value>) -> Value {
+    json!({ "name": name, "type": { "kind": "struct", "fields": fields } })
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_idl() {
+        let program_id = Pubkey::new_unique();
+        let idl = idl(&program_id);
+
+        assert_eq!(idl["address"], program_id.to_string());
+        let instructions = idl["instructions"].as_array().unwrap();
+        assert_eq!(instructions.len(), anchor::INSTRUCTIONS.len());
+        assert_eq!(instructions[9]["name"], "compare_and_swap");
+        assert_eq!(
+            instructions[9]["discriminator"],
+            json!(anchor::INSTRUCTIONS[9].1)
+        );
+        assert_eq!(instructions[9]["args"][1]["name"], "new");
+
+        let errors = idl["errors"].as_array().unwrap();
+        assert_eq!(errors[0]["name"], "Unauthorized");
+        assert_eq!(errors[20]["code"], CounterError::MissingReturnData as u32);
+        assert_eq!(
+            errors[20]["msg"],
+            CounterError::MissingReturnData.to_string()
+        );
+
+        // Every type referenced by name is declared.
+        let declared: Vec<&Value> = idl["types"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|ty| &ty["name"])
+            .collect();
+        let json = idl.to_string();
+        for reference in json.split(r#""defined":{"name":"#).skip(1) {
+            let name = reference.split('"').nth(1).unwrap();
+            assert!(declared.contains(&&json!(name)), "{}", name);
+        }
+    }
+}
@@ -1,30 +1,406 @@
-use borsh::{BorshDeserialize, BorshSerialize};
-use solana_program::program_error::ProgramError;
+use crate::error::CounterError;
+use crate::events::Operation;
+use crate::state::{Bounds, CounterKind, CounterValue, OverflowPolicy, Role, UnderflowPolicy};
+use borsh::{io, BorshDeserialize, BorshSerialize};
+use solana_program::{program_error::ProgramError, pubkey::Pubkey};
 
-#[derive(Debug, BorshSerialize, BorshDeserialize)]
+#[derive(Clone, Debug, PartialEq, Eq, BorshSerialize, BorshDeserialize)]
 pub struct UpdateArgs {
-    pub value: u32,
+    /// Amount or new value; must be of the same kind as the counter.
+    pub value: CounterValue,
 }
 
-pub enum CounterInstructions {
-    Increment(UpdateArgs),
-    Decrement(UpdateArgs),
-    Update(UpdateArgs),
+#[derive(Clone, Debug, PartialEq, Eq, BorshSerialize, BorshDeserialize)]
+pub struct InitializeArgs {
+    pub seed: Vec<u8>,
+    pub kind: CounterKind,
+    pub overflow_policy: OverflowPolicy,
+    pub underflow_policy: UnderflowPolicy,
+    pub bounds: Bounds,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq, BorshSerialize, BorshDeserialize)]
+pub struct CompareAndSwapArgs {
+    pub expected: CounterValue,
+    pub new: CounterValue,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq, BorshSerialize, BorshDeserialize)]
+pub struct InitializeMultisigArgs {
+    /// Number of `signers` that have to sign for the multisig.
+    pub threshold: u8,
+    pub signers: Vec<Pubkey>,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq, BorshSerialize, BorshDeserialize)]
+pub struct FanOutArgs {
+    pub op: Op,
+    /// Number of multisig members passed as the last accounts when the authority is a
+    /// multisig, 0 otherwise.
+    pub signers: u8,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq, BorshSerialize, BorshDeserialize)]
+pub struct RoleArgs {
+    pub member: Pubkey,
+    pub role: Role,
+}
+
+/// A single counter operation, as carried by `CounterInstructions::Batch`.
+#[derive(Clone, Debug, PartialEq, Eq, BorshSerialize, BorshDeserialize)]
+pub enum Op {
+    Increment(CounterValue),
+    Decrement(CounterValue),
+    Update(CounterValue),
     Reset,
+    TryDecrement(CounterValue),
+    CompareAndSwap(CompareAndSwapArgs),
+}
+
+impl Op {
+    pub fn operation(&self) -> Operation {
+        match self {
+            Self::Increment(_) => Operation::Increment,
+            Self::Decrement(_) => Operation::Decrement,
+            Self::Update(_) => Operation::Update,
+            Self::Reset => Operation::Reset,
+            Self::TryDecrement(_) => Operation::TryDecrement,
+            Self::CompareAndSwap(_) => Operation::CompareAndSwap,
+        }
+    }
+
+    /// The role a key other than the counter authority needs for the operation.
+    pub fn role(&self) -> Role {
+        match self {
+            Self::Increment(_) => Role::Incrementer,
+            Self::Decrement(_) | Self::TryDecrement(_) => Role::Decrementer,
+            Self::Update(_) | Self::CompareAndSwap(_) => Role::Updater,
+            Self::Reset => Role::Resetter,
+        }
+    }
+}
+
+/// Instruction data is the borsh encoding of this enum: a one-byte tag followed by the
+/// arguments. The tags are pinned by the explicit discriminants rather than the declaration
+/// order; never change or reuse one.
+///
+/// Clients from before counter kinds existed send `Increment`, `Decrement` and `Update` as the
+/// tag followed by a bare little-endian `u32`. `unpack` still reads that form as a
+/// `CounterValue::U32`, but `pack` always writes the kind-tagged one.
+#[derive(Clone, Debug, PartialEq, Eq, BorshSerialize, BorshDeserialize)]
+#[borsh(use_discriminant = true)]
+#[repr(u8)]
+pub enum CounterInstructions {
+    Increment(UpdateArgs) = 0,
+    Decrement(UpdateArgs) = 1,
+    Update(UpdateArgs) = 2,
+    Reset = 3,
+    Initialize(InitializeArgs) = 4,
+    /// Like `Decrement`, but fails instead of saturating when the value would drop below the
+    /// minimum of the counter kind. Dropping below the lower bound is left to the bounds policy.
+    TryDecrement(UpdateArgs) = 5,
+    /// Moves a legacy 4-byte counter to the current account layout.
+    Migrate = 6,
+    /// Deletes the counter, and the permissions accounts passed with it, and sends their lamports
+    /// to a destination account.
+    Close = 7,
+    /// Replaces the range the counter has to stay in.
+    SetBounds(Bounds) = 8,
+    /// Writes `new` only if the counter currently holds `expected`.
+    CompareAndSwap(CompareAndSwapArgs) = 9,
+    /// Applies the operations in order; if any of them fails, none of them takes effect.
+    Batch(Vec<Op>) = 10,
+    /// Applies the same operation to every counter account passed after the authority. On
+    /// failure the position of the failing counter in the instruction accounts is returned as
+    /// a little-endian `u32` through `set_return_data`.
+    FanOut(FanOutArgs) = 11,
+    /// Returns the counter through `set_return_data` without modifying it.
+    Get = 12,
+    /// Proposes a new authority; the current one stays in control until it is accepted.
+    ProposeAuthority(Pubkey) = 13,
+    /// Signed by the proposed authority to take over the counter.
+    AcceptAuthority = 14,
+    /// Withdraws the pending proposal.
+    CancelProposal = 15,
+    /// Sets up a `Multisig` in an account already created for the program. Its address can then
+    /// be made a counter authority through `ProposeAuthority` and `AcceptAuthority`.
+    InitializeMultisig(InitializeMultisigArgs) = 16,
+    /// Gives `member` a role on the counter, creating its permissions account if needed.
+    GrantRole(RoleArgs) = 17,
+    /// Takes a role away from `member`.
+    RevokeRole(RoleArgs) = 18,
+    /// Deletes the permissions account of a member and sends its lamports to a destination
+    /// account.
+    ClosePermissions = 19,
 }
 
 impl CounterInstructions {
+    /// Decodes instruction data, rejecting unknown tags and any bytes left over after the
+    /// arguments.
+    ///
+    /// With the `anchor` feature the tag may also be the 8-byte Anchor sighash of the
+    /// instruction. No native encoding starts with one of those, so both forms are accepted.
     pub fn unpack(input: &[u8]) -> Result<Self, ProgramError> {
-        let (&variant, rest) = input
-            .split_first()
-            .ok_or(ProgramError::InvalidInstructionData)?;
-
-        Ok(match variant {
-            0 => Self::Increment(UpdateArgs::try_from_slice(rest).unwrap()),
-            1 => Self::Decrement(UpdateArgs::try_from_slice(rest).unwrap()),
-            2 => Self::Update(UpdateArgs::try_from_slice(rest).unwrap()),
-            3 => Self::Reset,
-            _ => return Err(ProgramError::InvalidInstructionData),
-        })
+        #[cfg(feature = "anchor")]
+        if let Some(tag) = crate::anchor::instruction_tag(input) {
+            let mut data = vec![tag];
+            data.extend_from_slice(&input[8..]);
+            return Self::unpack_native(&data);
+        }
+        // A kind-tagged value takes at least 5 bytes, so 4 bytes after the tag are the old form.
+        if let [tag @ 0..=2, value @ ..] = input {
+            if let Ok(bits) = <[u8; 4]>::try_from(value) {
+                let args = UpdateArgs {
+                    value: CounterValue::U32(u32::from_le_bytes(bits)),
+                };
+                return Ok(match tag {
+                    0 => Self::Increment(args),
+                    1 => Self::Decrement(args),
+                    _ => Self::Update(args),
+                });
+            }
+        }
+        Self::unpack_native(input)
+    }
+
+    fn unpack_native(input: &[u8]) -> Result<Self, ProgramError> {
+        if input.is_empty() {
+            return Err(CounterError::InvalidInstructionTag.into());
+        }
+        Ok(unpack_args(input)?)
+    }
+
+    /// Encodes the instruction the way `unpack` reads it.
+    pub fn pack(&self) -> Vec<u8> {
+        // Writing into a `Vec` cannot fail.
+        borsh::to_vec(self).unwrap()
+    }
+}
+
+/// Decodes `T` from the whole of `input`, distinguishing an unknown tag and data that ran out
+/// early from data that was left over or could not be decoded.
+fn unpack_args<T: BorshDeserialize>(input: &[u8]) -> Result<T, CounterError> {
+    let mut reader = ArgsReader {
+        input,
+        truncated: false,
+    };
+    let args = T::deserialize_reader(&mut reader).map_err(|_| {
+        if reader.truncated {
+            CounterError::TruncatedInstructionData
+        } else if reader.input.len() + 1 == input.len() {
+            // Every argument is at least one byte long, so failing right after the first
+            // byte means the tag itself was not recognised.
+            CounterError::InvalidInstructionTag
+        } else {
+            CounterError::MalformedInstructionData
+        }
+    })?;
+    if !reader.input.is_empty() {
+        return Err(CounterError::TrailingInstructionData);
+    }
+    Ok(args)
+}
+
+struct ArgsReader<'a> {
+    input: &'a [u8],
+    truncated: bool,
+}
+
+impl io::Read for ArgsReader<'_> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if buf.len() > self.input.len() {
+            self.truncated = true;
+        }
+        self.input.read(buf)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_unpack_rejects_malformed_data() {
+        let cases: &[(&[u8], CounterError)] = &[
+            (&[], CounterError::InvalidInstructionTag),
+            (&[42], CounterError::InvalidInstructionTag),
+            (&[20, 0, 0, 0], CounterError::InvalidInstructionTag),
+            (&[13, 0, 0, 0], CounterError::TruncatedInstructionData),
+            (&[0, 0, 1, 0], CounterError::TruncatedInstructionData),
+            (&[0, 1, 1, 0, 0, 0], CounterError::TruncatedInstructionData),
+            (
+                &[0, 0, 1, 0, 0, 0, 9],
+                CounterError::TrailingInstructionData,
+            ),
+            (&[0, 3, 1, 0, 0, 0], CounterError::MalformedInstructionData),
+            (&[3, 0], CounterError::TrailingInstructionData),
+            (&[12, 0], CounterError::TrailingInstructionData),
+            (&[4, 5, 0, 0, 0, 1], CounterError::TruncatedInstructionData),
+            (
+                &[4, 0, 0, 0, 0, 0, 3, 0, 0, 0, 0],
+                CounterError::MalformedInstructionData,
+            ),
+        ];
+        for (data, expected) in cases {
+            assert_eq!(
+                CounterInstructions::unpack(data).err(),
+                Some((*expected).into()),
+                "data: {:?}",
+                data
+            );
+        }
+        assert_eq!(
+            CounterInstructions::unpack(&[2, 1, 33, 0, 0, 0, 0, 0, 0, 0]),
+            Ok(CounterInstructions::Update(UpdateArgs {
+                value: CounterValue::U64(33)
+            }))
+        );
+        // The encoding used before values were kind-tagged.
+        assert_eq!(
+            CounterInstructions::unpack(&[0, 7, 1, 0, 0]),
+            Ok(CounterInstructions::Increment(UpdateArgs {
+                value: CounterValue::U32(263)
+            }))
+        );
+        assert_eq!(
+            CounterInstructions::unpack(&[2, 33, 0, 0, 0]),
+            Ok(CounterInstructions::Update(UpdateArgs {
+                value: CounterValue::U32(33)
+            }))
+        );
+    }
+
+    #[test]
+    fn test_anchor_sighashes_are_not_native_instructions() {
+        for (name, sighash) in crate::anchor::INSTRUCTIONS {
+            assert!(
+                CounterInstructions::unpack_native(&sighash).is_err(),
+                "{}",
+                name
+            );
+        }
+    }
+
+    #[cfg(feature = "anchor")]
+    #[test]
+    fn test_unpack_anchor_sighash() {
+        let instruction = CounterInstructions::CompareAndSwap(CompareAndSwapArgs {
+            expected: CounterValue::U32(1),
+            new: CounterValue::U32(2),
+        });
+        let native = instruction.pack();
+        let mut data = crate::anchor::INSTRUCTIONS[9].1.to_vec();
+        data.extend_from_slice(&native[1..]);
+        assert_eq!(CounterInstructions::unpack(&data), Ok(instruction));
+        assert_eq!(
+            CounterInstructions::unpack(&native),
+            CounterInstructions::unpack(&data)
+        );
+
+        let mut data = crate::anchor::INSTRUCTIONS[3].1.to_vec();
+        data.push(0);
+        assert_eq!(
+            CounterInstructions::unpack(&data),
+            Err(CounterError::TrailingInstructionData.into())
+        );
+    }
+
+    #[test]
+    fn test_pinned_tags() {
+        let value = UpdateArgs {
+            value: CounterValue::U32(5),
+        };
+        let cases = [
+            (CounterInstructions::Increment(value.clone()), 0),
+            (CounterInstructions::Decrement(value.clone()), 1),
+            (CounterInstructions::Update(value.clone()), 2),
+            (CounterInstructions::Reset, 3),
+            (CounterInstructions::TryDecrement(value), 5),
+            (CounterInstructions::Migrate, 6),
+            (CounterInstructions::Close, 7),
+            (CounterInstructions::Get, 12),
+            (CounterInstructions::AcceptAuthority, 14),
+            (CounterInstructions::CancelProposal, 15),
+            (CounterInstructions::ClosePermissions, 19),
+        ];
+        for (instruction, tag) in cases {
+            assert_eq!(instruction.pack()[0], tag, "{:?}", instruction);
+        }
+        assert_eq!(
+            CounterInstructions::Update(UpdateArgs {
+                value: CounterValue::U32(5)
+            })
+            .pack(),
+            [2, 0, 5, 0, 0, 0]
+        );
+    }
+
+    #[test]
+    fn test_pack_unpack_round_trip() {
+        let value = UpdateArgs {
+            value: CounterValue::I64(-7),
+        };
+        let compare_and_swap = CompareAndSwapArgs {
+            expected: CounterValue::U32(1),
+            new: CounterValue::U32(2),
+        };
+        let bounds = Bounds {
+            min: Some(CounterValue::U64(1)),
+            max: Some(CounterValue::U64(100)),
+            policy: crate::state::BoundsPolicy::Clamp,
+        };
+        let instructions = [
+            CounterInstructions::Increment(value.clone()),
+            CounterInstructions::Decrement(value.clone()),
+            CounterInstructions::Update(value.clone()),
+            CounterInstructions::Reset,
+            CounterInstructions::Initialize(InitializeArgs {
+                seed: b"seed".to_vec(),
+                kind: CounterKind::U64,
+                overflow_policy: OverflowPolicy::Wrap,
+                underflow_policy: UnderflowPolicy::Error,
+                bounds,
+            }),
+            CounterInstructions::TryDecrement(value),
+            CounterInstructions::Migrate,
+            CounterInstructions::Close,
+            CounterInstructions::SetBounds(bounds),
+            CounterInstructions::CompareAndSwap(compare_and_swap.clone()),
+            CounterInstructions::Batch(vec![
+                Op::Increment(CounterValue::U32(1)),
+                Op::Decrement(CounterValue::U32(1)),
+                Op::Update(CounterValue::U32(1)),
+                Op::Reset,
+                Op::TryDecrement(CounterValue::U32(1)),
+                Op::CompareAndSwap(compare_and_swap),
+            ]),
+            CounterInstructions::FanOut(FanOutArgs {
+                op: Op::Reset,
+                signers: 2,
+            }),
+            CounterInstructions::Get,
+            CounterInstructions::ProposeAuthority(Pubkey::new_unique()),
+            CounterInstructions::AcceptAuthority,
+            CounterInstructions::CancelProposal,
+            CounterInstructions::InitializeMultisig(InitializeMultisigArgs {
+                threshold: 2,
+                signers: vec![Pubkey::new_unique(), Pubkey::new_unique()],
+            }),
+            CounterInstructions::GrantRole(RoleArgs {
+                member: Pubkey::new_unique(),
+                role: Role::Resetter,
+            }),
+            CounterInstructions::RevokeRole(RoleArgs {
+                member: Pubkey::new_unique(),
+                role: Role::Admin,
+            }),
+            CounterInstructions::ClosePermissions,
+        ];
+        for instruction in instructions {
+            assert_eq!(
+                CounterInstructions::unpack(&instruction.pack()),
+                Ok(instruction)
+            );
+        }
     }
 }
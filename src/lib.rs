@@ -1,25 +1,79 @@
-mod instructions;
+//! Counter program.
+//!
+//! Build with the `no-entrypoint` feature to link the crate into another program for its
+//! account and instruction types without a second `entrypoint` symbol, and with `cpi` for the
+//! invoke helpers in the `cpi` module. Clients build transactions with the `builder` module.
+//!
+//! The `anchor` feature switches the account and event discriminators to Anchor's and also
+//! accepts Anchor instruction sighashes, so Anchor clients can use the IDL from `idl::idl`.
 
-use crate::instructions::CounterInstructions;
-use borsh::{BorshDeserialize, BorshSerialize};
+pub mod anchor;
+pub mod builder;
+#[cfg(feature = "cpi")]
+pub mod cpi;
+pub mod error;
+pub mod events;
+#[cfg(not(target_os = "solana"))]
+pub mod idl;
+pub mod instructions;
+pub mod state;
+pub mod transition;
+
+pub use crate::state::{
+    Bounds, BoundsPolicy, CounterAccount, CounterKind, CounterState, CounterValue, Multisig,
+    OverflowPolicy, Permissions, Role, Roles, UnderflowPolicy,
+};
+
+use crate::error::CounterError;
+use crate::events::{CounterEvent, Operation};
+use crate::instructions::{
+    CounterInstructions, FanOutArgs, InitializeArgs, InitializeMultisigArgs, RoleArgs,
+};
+use crate::state::{CounterData, CLOSED_DISCRIMINATOR, LEGACY_LEN, PERMISSIONS_DISCRIMINATOR};
+use crate::transition::{apply_in_place, Context};
+use borsh::BorshDeserialize;
 use solana_program::{
     account_info::{next_account_info, AccountInfo},
-    entrypoint,
+    bpf_loader_upgradeable::{self, UpgradeableLoaderState},
+    clock::Clock,
     entrypoint::ProgramResult,
     msg,
-    program_error::ProgramError,
+    program::{get_return_data, invoke, invoke_signed, set_return_data},
+    program_error::{PrintProgramError, ProgramError},
     pubkey::Pubkey,
+    rent::Rent,
+    system_instruction, system_program,
+    sysvar::Sysvar,
 };
+use std::cell::{Ref, RefMut};
+
+pub const COUNTER_SEED: &[u8] = b"counter";
+
+/// Derives the address of the counter created by `payer` with the given `seed`.
+pub fn find_counter_address(program_id: &Pubkey, payer: &Pubkey, seed: &[u8]) -> (Pubkey, u8) {
+    Pubkey::find_program_address(&[COUNTER_SEED, payer.as_ref(), seed], program_id)
+}
+
+pub const PERMISSIONS_SEED: &[u8] = b"permissions";
 
-#[derive(Debug, BorshDeserialize, BorshSerialize)]
-pub struct CounterAccount {
-    pub counter: u32,
+/// Derives the address of the account holding the roles of `member` on `counter`.
+pub fn find_permissions_address(
+    program_id: &Pubkey,
+    counter: &Pubkey,
+    member: &Pubkey,
+) -> (Pubkey, u8) {
+    Pubkey::find_program_address(
+        &[PERMISSIONS_SEED, counter.as_ref(), member.as_ref()],
+        program_id,
+    )
 }
 
+#[allow(dead_code)]
 trait InputProvider {
     fn get_input(&self) -> String;
 }
 
+#[allow(dead_code)]
 struct StdInputProvider;
 
 impl InputProvider for StdInputProvider {
@@ -30,49 +84,913 @@ impl InputProvider for StdInputProvider {
     }
 }
 
-entrypoint!(process_instruction);
+#[cfg(not(feature = "no-entrypoint"))]
+solana_program::entrypoint!(process_instruction);
 
 pub fn process_instruction(
-    _program_id: &Pubkey,
+    program_id: &Pubkey,
     accounts: &[AccountInfo],
     instructions_data: &[u8],
 ) -> ProgramResult {
     msg!("Counter program entry point");
 
+    if let Err(error) = process(program_id, accounts, instructions_data) {
+        error.print::<CounterError>();
+        return Err(error);
+    }
+    Ok(())
+}
+
+fn process(
+    program_id: &Pubkey,
+    accounts: &[AccountInfo],
+    instructions_data: &[u8],
+) -> ProgramResult {
     let instruction: CounterInstructions = CounterInstructions::unpack(instructions_data)?;
+    match instruction {
+        CounterInstructions::Initialize(args) => process_initialize(program_id, accounts, args),
+        CounterInstructions::Migrate => process_migrate(program_id, accounts),
+        CounterInstructions::Close => process_close(program_id, accounts),
+        CounterInstructions::FanOut(args) => process_fan_out(program_id, accounts, args),
+        CounterInstructions::Get => process_get(program_id, accounts),
+        CounterInstructions::InitializeMultisig(args) => {
+            process_initialize_multisig(program_id, accounts, args)
+        }
+        CounterInstructions::GrantRole(args) => process_grant_role(program_id, accounts, args),
+        CounterInstructions::RevokeRole(args) => process_revoke_role(program_id, accounts, args),
+        CounterInstructions::ClosePermissions => process_close_permissions(program_id, accounts),
+        instruction => process_update(program_id, accounts, instruction),
+    }
+}
+
+/// Validates a counter account that is about to be modified and borrows its data as a
+/// `CounterData`, which instructions then change in place.
+fn load_counter_mut<'a>(
+    program_id: &Pubkey,
+    account: &'a AccountInfo,
+) -> Result<RefMut<'a, CounterData>, ProgramError> {
+    if !account.is_writable {
+        return Err(CounterError::AccountNotWritable.into());
+    }
+    check_counter(program_id, account)?;
+    Ok(RefMut::map(account.try_borrow_mut_data()?, |data| {
+        bytemuck::from_bytes_mut(&mut data[..CounterAccount::LEN])
+    }))
+}
+
+/// Validates the counter account before its data is trusted and borrows it as a `CounterData`.
+fn load_counter<'a>(
+    program_id: &Pubkey,
+    account: &'a AccountInfo,
+) -> Result<Ref<'a, CounterData>, ProgramError> {
+    check_counter(program_id, account)?;
+    Ok(Ref::map(account.try_borrow_data()?, |data| {
+        bytemuck::from_bytes(&data[..CounterAccount::LEN])
+    }))
+}
+
+fn check_counter(program_id: &Pubkey, account: &AccountInfo) -> ProgramResult {
+    if account.owner != program_id {
+        msg!("Counter {} is owned by {}", account.key, account.owner);
+        return Err(CounterError::IncorrectAccountOwner.into());
+    }
+    if account.data_len() != CounterAccount::LEN && account.data_len() != LEGACY_LEN {
+        return Err(CounterError::InvalidAccountDataLength.into());
+    }
+    CounterData::load(&account.try_borrow_data()?)?;
+    if !Rent::get()?.is_exempt(account.lamports(), account.data_len()) {
+        return Err(CounterError::AccountNotRentExempt.into());
+    }
+    Ok(())
+}
+
+/// Checks that `authority` signed, or its multisig members did, and that it is the authority
+/// stored in the counter.
+fn check_authority(
+    program_id: &Pubkey,
+    counter: &impl CounterState,
+    authority: &AccountInfo,
+    signers: &[AccountInfo],
+) -> ProgramResult {
+    check_signer(program_id, counter, authority, signers)?;
+    if counter.authority() != *authority.key {
+        msg!("Signer {} is not the counter authority", authority.key);
+        return Err(CounterError::Unauthorized.into());
+    }
+    Ok(())
+}
+
+/// Checks that `signer` signed or, when it is a `Multisig`, that at least `threshold` of its
+/// members sign among `signers`. Whether that signer may send the instruction is left to
+/// `transition::apply`.
+fn check_signer(
+    program_id: &Pubkey,
+    counter: &impl CounterState,
+    signer: &AccountInfo,
+    signers: &[AccountInfo],
+) -> ProgramResult {
+    if !counter.is_initialized() {
+        return Err(ProgramError::UninitializedAccount);
+    }
+    // A multisig is always checked against its members, even when the key that created the
+    // account signs as well; anyone holding that key could otherwise skip the threshold.
+    let data = signer.try_borrow_data()?;
+    let multisig = match Multisig::load(&data) {
+        Ok(multisig) if signer.owner == program_id => multisig,
+        _ if signer.is_signer => return Ok(()),
+        _ => return Err(ProgramError::MissingRequiredSignature),
+    };
+    let signed = multisig
+        .signers()
+        .iter()
+        .filter(|member| {
+            signers
+                .iter()
+                .any(|account| account.is_signer && account.key == *member)
+        })
+        .count();
+    if signed < usize::from(multisig.threshold()) {
+        msg!(
+            "{} of {} required multisig signers signed",
+            signed,
+            multisig.threshold()
+        );
+        return Err(CounterError::NotEnoughSigners.into());
+    }
+    Ok(())
+}
+
+/// Returns the roles `member` holds on `counter`, whose nonce is `nonce`, according to its
+/// permissions account among `accounts`, or no roles if it was not passed.
+fn load_roles(
+    program_id: &Pubkey,
+    counter: &Pubkey,
+    nonce: u64,
+    member: &Pubkey,
+    accounts: &[AccountInfo],
+) -> Roles {
+    accounts
+        .iter()
+        .filter(|account| account.owner == program_id)
+        .find_map(|account| {
+            let data = account.try_borrow_data().ok()?;
+            let permissions = Permissions::load(&data).ok()?;
+            (permissions.counter() == counter && permissions.member() == member)
+                .then_some(permissions.roles_for(nonce))
+        })
+        .unwrap_or_default()
+}
+
+fn is_permissions(program_id: &Pubkey, account: &AccountInfo) -> bool {
+    account.owner == program_id
+        && account
+            .try_borrow_data()
+            .is_ok_and(|data| data.starts_with(&PERMISSIONS_DISCRIMINATOR))
+}
+
+/// Applies a counter operation on behalf of the counter authority, a member holding the
+/// matching role, or the pending authority for `AcceptAuthority`.
+///
+/// Accounts: `[writable] counter`, `[signer] authority` or member, then the member's `[]`
+/// permissions account and `[signer]` multisig members when the signer is a multisig.
+fn process_update(
+    program_id: &Pubkey,
+    accounts: &[AccountInfo],
+    instruction: CounterInstructions,
+) -> ProgramResult {
     let accounts_iter = &mut accounts.iter();
     let account = next_account_info(accounts_iter)?;
+    let authority = next_account_info(accounts_iter)?;
 
-    let mut counter_account = CounterAccount::try_from_slice(&account.data.borrow())?;
+    let mut counter = load_counter_mut(program_id, account)?;
+    let rest = accounts_iter.as_slice();
+    check_signer(program_id, &*counter, authority, rest)?;
+    let context = Context {
+        counter: *account.key,
+        signer: *authority.key,
+        slot: Clock::get()?.slot,
+        roles: load_roles(
+            program_id,
+            account.key,
+            counter.nonce(),
+            authority.key,
+            rest,
+        ),
+    };
 
-    match instruction {
-        CounterInstructions::Increment(args) => {
-            counter_account.counter += args.value;
-        }
-        CounterInstructions::Decrement(args) => {
-            if counter_account.counter >= args.value {
-                counter_account.counter -= args.value
-            } else {
-                counter_account.counter = 0
-            }
+    let events = apply_instruction(&mut *counter, &instruction, &context)?;
+    events.iter().for_each(CounterEvent::emit);
+    Ok(())
+}
+
+/// Runs `instruction` on the counter through `transition::apply_in_place` and logs why it was
+/// rejected.
+fn apply_instruction(
+    counter: &mut (impl CounterState + Clone),
+    instruction: &CounterInstructions,
+    context: &Context,
+) -> Result<Vec<CounterEvent>, ProgramError> {
+    apply_in_place(counter, instruction, context).map_err(|rejection| {
+        if let Some(index) = rejection.batch_op {
+            msg!("Batch op {} failed", index);
+        }
+        if rejection.error == CounterError::Unauthorized {
+            msg!("Signer {} may not send the instruction", context.signer);
+        }
+        rejection.error.into()
+    })
+}
+
+/// Applies `args.op` to every counter account. Each counter is validated on its own and must
+/// be controlled by the same authority, or grant the signer the role of the op. The position
+/// of the first failing counter among the instruction accounts is logged and set as return
+/// data.
+///
+/// Accounts: `[signer] authority` or member, `[writable] counter` one or more times, the
+/// member's `[]` permissions accounts for those counters, then `args.signers` `[signer]`
+/// multisig members when the signer is a multisig.
+fn process_fan_out(
+    program_id: &Pubkey,
+    accounts: &[AccountInfo],
+    args: FanOutArgs,
+) -> ProgramResult {
+    let (authority, rest) = accounts
+        .split_first()
+        .ok_or(ProgramError::NotEnoughAccountKeys)?;
+    let counters_len = rest
+        .len()
+        .checked_sub(usize::from(args.signers))
+        .ok_or(ProgramError::NotEnoughAccountKeys)?;
+    let (counters, signers) = rest.split_at(counters_len);
+    // Positions count from the authority, so they match the accounts of the instruction.
+    let counters: Vec<(usize, &AccountInfo)> = counters
+        .iter()
+        .enumerate()
+        .map(|(index, account)| (index + 1, account))
+        .filter(|(_, account)| !is_permissions(program_id, account))
+        .collect();
+    if counters.is_empty() {
+        return Err(ProgramError::NotEnoughAccountKeys);
+    }
+    let slot = Clock::get()?.slot;
+    let instruction = CounterInstructions::FanOut(args);
+
+    for (index, account) in counters {
+        let result = load_counter_mut(program_id, account).and_then(|mut counter| {
+            check_signer(program_id, &*counter, authority, signers)?;
+            let context = Context {
+                counter: *account.key,
+                signer: *authority.key,
+                slot,
+                roles: load_roles(
+                    program_id,
+                    account.key,
+                    counter.nonce(),
+                    authority.key,
+                    rest,
+                ),
+            };
+            let events = apply_instruction(&mut *counter, &instruction, &context)?;
+            events.iter().for_each(CounterEvent::emit);
+            Ok(())
+        });
+        if let Err(error) = result {
+            msg!("Fan-out failed at account {} ({})", index, account.key);
+            set_return_data(&(index as u32).to_le_bytes());
+            return Err(error);
+        }
+    }
+    Ok(())
+}
+
+/// Returns the borsh-encoded `CounterAccount` through `set_return_data`, so programs calling in
+/// through CPI can read it with `get_counter_return_data`.
+///
+/// Accounts: `[] counter`.
+fn process_get(program_id: &Pubkey, accounts: &[AccountInfo]) -> ProgramResult {
+    let accounts_iter = &mut accounts.iter();
+    let account = next_account_info(accounts_iter)?;
+
+    let counter_account = load_counter(program_id, account)?.account();
+    set_return_data(&borsh::to_vec(&counter_account)?);
+    Ok(())
+}
+
+/// Reads the counter returned by a `Get` instruction that was just invoked through CPI.
+pub fn get_counter_return_data(program_id: &Pubkey) -> Result<CounterAccount, ProgramError> {
+    match get_return_data() {
+        Some((returned_by, data)) if returned_by == *program_id => {
+            Ok(CounterAccount::try_from_slice(&data)?)
+        }
+        _ => Err(CounterError::MissingReturnData.into()),
+    }
+}
+
+/// Creates the counter PDA for `payer` and `args.seed` and makes the payer its authority. The
+/// counter starts at zero, or at the nearest bound if zero is out of range.
+///
+/// Accounts: `[writable] counter`, `[signer, writable] payer`, `[] system program`.
+fn process_initialize(
+    program_id: &Pubkey,
+    accounts: &[AccountInfo],
+    args: InitializeArgs,
+) -> ProgramResult {
+    let accounts_iter = &mut accounts.iter();
+    let account = next_account_info(accounts_iter)?;
+    let payer = next_account_info(accounts_iter)?;
+    let system_program_info = next_account_info(accounts_iter)?;
+
+    if !payer.is_signer {
+        return Err(ProgramError::MissingRequiredSignature);
+    }
+    if !system_program::check_id(system_program_info.key) {
+        return Err(ProgramError::IncorrectProgramId);
+    }
+    if args.seed.len() > solana_program::pubkey::MAX_SEED_LEN {
+        return Err(ProgramError::MaxSeedLengthExceeded);
+    }
+    args.bounds.check(args.kind)?;
+
+    let (address, bump) = find_counter_address(program_id, payer.key, &args.seed);
+    if address != *account.key {
+        return Err(ProgramError::InvalidSeeds);
+    }
+    if !account.data_is_empty() {
+        return Err(ProgramError::AccountAlreadyInitialized);
+    }
+
+    let signer_seeds: &[&[u8]] = &[COUNTER_SEED, payer.key.as_ref(), &args.seed, &[bump]];
+    create_pda(
+        program_id,
+        account,
+        payer,
+        system_program_info,
+        CounterAccount::LEN,
+        signer_seeds,
+    )?;
+
+    let counter_account = CounterAccount {
+        authority: *payer.key,
+        // Starts in range even when the bounds exclude zero and reject out-of-range values.
+        counter: args.bounds.nearest(CounterValue::zero(args.kind)),
+        overflow_policy: args.overflow_policy,
+        underflow_policy: args.underflow_policy,
+        bounds: args.bounds,
+        pending_authority: None,
+    };
+    let slot = Clock::get()?.slot;
+    let mut data = account.data.borrow_mut();
+    counter_account.pack(&mut data)?;
+    CounterData::load_mut(&mut data)?.set_nonce(slot);
+    msg!("Initialized counter {} for {}", account.key, payer.key);
+    Context {
+        counter: *account.key,
+        signer: *payer.key,
+        slot,
+        roles: Roles::default(),
+    }
+    .event(Operation::Initialize, None, Some(counter_account.counter))
+    .emit();
+    Ok(())
+}
+
+/// Creates the rent-exempt PDA `account` with `space` bytes, owned by the program and paid for
+/// by `payer`.
+fn create_pda<'a>(
+    program_id: &Pubkey,
+    account: &AccountInfo<'a>,
+    payer: &AccountInfo<'a>,
+    system_program_info: &AccountInfo<'a>,
+    space: usize,
+    signer_seeds: &[&[u8]],
+) -> ProgramResult {
+    let required_lamports = Rent::get()?.minimum_balance(space);
+    if account.lamports() == 0 {
+        invoke_signed(
+            &system_instruction::create_account(
+                payer.key,
+                account.key,
+                required_lamports,
+                space as u64,
+                program_id,
+            ),
+            &[payer.clone(), account.clone(), system_program_info.clone()],
+            &[signer_seeds],
+        )?;
+    } else {
+        // Someone already sent lamports to the address, which makes `create_account` fail.
+        // Top the balance up and allocate/assign the account directly instead.
+        let shortfall = required_lamports.saturating_sub(account.lamports());
+        if shortfall > 0 {
+            invoke(
+                &system_instruction::transfer(payer.key, account.key, shortfall),
+                &[payer.clone(), account.clone(), system_program_info.clone()],
+            )?;
+        }
+        invoke_signed(
+            &system_instruction::allocate(account.key, space as u64),
+            &[account.clone(), system_program_info.clone()],
+            &[signer_seeds],
+        )?;
+        invoke_signed(
+            &system_instruction::assign(account.key, program_id),
+            &[account.clone(), system_program_info.clone()],
+            &[signer_seeds],
+        )?;
+    }
+    Ok(())
+}
+
+/// Writes a `Multisig` into an account the client created for the program beforehand, in the
+/// same transaction so nobody else can initialize it first.
+///
+/// Accounts: `[writable] multisig`.
+fn process_initialize_multisig(
+    program_id: &Pubkey,
+    accounts: &[AccountInfo],
+    args: InitializeMultisigArgs,
+) -> ProgramResult {
+    let accounts_iter = &mut accounts.iter();
+    let account = next_account_info(accounts_iter)?;
+
+    if account.owner != program_id {
+        return Err(CounterError::IncorrectAccountOwner.into());
+    }
+    if !account.is_writable {
+        return Err(CounterError::AccountNotWritable.into());
+    }
+    if account.data_len() != Multisig::LEN {
+        return Err(CounterError::InvalidAccountDataLength.into());
+    }
+    if !Rent::get()?.is_exempt(account.lamports(), account.data_len()) {
+        return Err(CounterError::AccountNotRentExempt.into());
+    }
+    let mut data = account.data.borrow_mut();
+    if data.iter().any(|&byte| byte != 0) {
+        return Err(ProgramError::AccountAlreadyInitialized);
+    }
+
+    Multisig::new(args.threshold, &args.signers)?.pack(&mut data)?;
+    msg!(
+        "Initialized {}-of-{} multisig {}",
+        args.threshold,
+        args.signers.len(),
+        account.key
+    );
+    Ok(())
+}
+
+/// Gives `args.member` a role on the counter. The permissions PDA is created on the first
+/// grant, paid for by `payer`.
+///
+/// Accounts: `[] counter`, `[signer] authority` or admin, `[writable] permissions` of the
+/// member, `[signer, writable] payer`, `[] system program`, then the admin's `[]` permissions
+/// account and `[signer]` multisig members when the signer is a multisig.
+fn process_grant_role(
+    program_id: &Pubkey,
+    accounts: &[AccountInfo],
+    args: RoleArgs,
+) -> ProgramResult {
+    let accounts_iter = &mut accounts.iter();
+    let account = next_account_info(accounts_iter)?;
+    let authority = next_account_info(accounts_iter)?;
+    let permissions = next_account_info(accounts_iter)?;
+    let payer = next_account_info(accounts_iter)?;
+    let system_program_info = next_account_info(accounts_iter)?;
+
+    let (nonce, events) = check_role_change(
+        program_id,
+        account,
+        authority,
+        accounts_iter.as_slice(),
+        &CounterInstructions::GrantRole(args.clone()),
+    )?;
+    let bump = check_permissions_account(program_id, account, permissions, &args.member)?;
+    if permissions.data_is_empty() {
+        if !payer.is_signer {
+            return Err(ProgramError::MissingRequiredSignature);
+        }
+        if !system_program::check_id(system_program_info.key) {
+            return Err(ProgramError::IncorrectProgramId);
+        }
+        let signer_seeds: &[&[u8]] = &[
+            PERMISSIONS_SEED,
+            account.key.as_ref(),
+            args.member.as_ref(),
+            &[bump],
+        ];
+        create_pda(
+            program_id,
+            permissions,
+            payer,
+            system_program_info,
+            Permissions::LEN,
+            signer_seeds,
+        )?;
+        Permissions::new(*account.key, args.member, nonce)
+            .pack(&mut permissions.data.borrow_mut())?;
+    }
+
+    let mut data = permissions.data.borrow_mut();
+    let entry = Permissions::load_mut(&mut data)?;
+    entry.set_roles(nonce, entry.roles_for(nonce).with(args.role));
+    msg!(
+        "Granted {:?} on {} to {}",
+        args.role,
+        account.key,
+        args.member
+    );
+    events.iter().for_each(CounterEvent::emit);
+    Ok(())
+}
+
+/// Takes a role away from `args.member`. The permissions account is kept, so granting a role
+/// again does not need a payer to create it.
+///
+/// Accounts: `[] counter`, `[signer] authority` or admin, `[writable] permissions` of the
+/// member, then the admin's `[]` permissions account and `[signer]` multisig members when the
+/// signer is a multisig.
+fn process_revoke_role(
+    program_id: &Pubkey,
+    accounts: &[AccountInfo],
+    args: RoleArgs,
+) -> ProgramResult {
+    let accounts_iter = &mut accounts.iter();
+    let account = next_account_info(accounts_iter)?;
+    let authority = next_account_info(accounts_iter)?;
+    let permissions = next_account_info(accounts_iter)?;
+
+    let (nonce, events) = check_role_change(
+        program_id,
+        account,
+        authority,
+        accounts_iter.as_slice(),
+        &CounterInstructions::RevokeRole(args.clone()),
+    )?;
+    check_permissions_account(program_id, account, permissions, &args.member)?;
+    // A member without a permissions account holds no role to revoke.
+    if !permissions.data_is_empty() {
+        let mut data = permissions.data.borrow_mut();
+        let entry = Permissions::load_mut(&mut data)?;
+        entry.set_roles(nonce, entry.roles_for(nonce).without(args.role));
+    }
+    msg!(
+        "Revoked {:?} on {} from {}",
+        args.role,
+        account.key,
+        args.member
+    );
+    events.iter().for_each(CounterEvent::emit);
+    Ok(())
+}
+
+/// Checks through `transition::apply` that `authority` may change roles on the counter, and
+/// returns the counter nonce the roles are granted under and the events to log once the
+/// change is made.
+fn check_role_change(
+    program_id: &Pubkey,
+    account: &AccountInfo,
+    authority: &AccountInfo,
+    rest: &[AccountInfo],
+    instruction: &CounterInstructions,
+) -> Result<(u64, Vec<CounterEvent>), ProgramError> {
+    let (nonce, mut counter_account) = {
+        let counter = load_counter(program_id, account)?;
+        (counter.nonce(), counter.account())
+    };
+    check_signer(program_id, &counter_account, authority, rest)?;
+    let context = Context {
+        counter: *account.key,
+        signer: *authority.key,
+        slot: Clock::get()?.slot,
+        roles: load_roles(program_id, account.key, nonce, authority.key, rest),
+    };
+    let events = apply_instruction(&mut counter_account, instruction, &context)?;
+    Ok((nonce, events))
+}
+
+/// Checks that `permissions` is the writable permissions PDA of `member` on the counter, owned
+/// by the program unless it is still empty, and returns its bump seed.
+fn check_permissions_account(
+    program_id: &Pubkey,
+    account: &AccountInfo,
+    permissions: &AccountInfo,
+    member: &Pubkey,
+) -> Result<u8, ProgramError> {
+    let (address, bump) = find_permissions_address(program_id, account.key, member);
+    if address != *permissions.key {
+        return Err(ProgramError::InvalidSeeds);
+    }
+    if !permissions.is_writable {
+        return Err(CounterError::AccountNotWritable.into());
+    }
+    if !permissions.data_is_empty() && permissions.owner != program_id {
+        return Err(CounterError::IncorrectAccountOwner.into());
+    }
+    Ok(bump)
+}
+
+/// Deletes the counter, sending its lamports to `destination`. The data is zeroed and stamped
+/// with `CLOSED_DISCRIMINATOR`, so the account cannot be used or re-initialized even if it is
+/// refunded before the end of the transaction.
+///
+/// The permissions accounts passed along are closed too. Pass all of them: any left behind
+/// would apply again to a counter created later at the same address, until their members close
+/// them with `ClosePermissions`.
+///
+/// Accounts: `[writable] counter`, `[signer] authority`, `[writable] destination`, then the
+/// `[writable]` permissions accounts of the counter and `[signer]` multisig members when the
+/// authority is a multisig.
+fn process_close(program_id: &Pubkey, accounts: &[AccountInfo]) -> ProgramResult {
+    let accounts_iter = &mut accounts.iter();
+    let account = next_account_info(accounts_iter)?;
+    let authority = next_account_info(accounts_iter)?;
+    let destination = next_account_info(accounts_iter)?;
+    let rest = accounts_iter.as_slice();
+
+    let counter = load_counter_mut(program_id, account)?;
+    check_authority(program_id, &*counter, authority, rest)?;
+    let value = counter.counter();
+    drop(counter);
+
+    let lamports = close_account(account, destination)?;
+    account.try_borrow_mut_data()?[..CLOSED_DISCRIMINATOR.len()]
+        .copy_from_slice(&CLOSED_DISCRIMINATOR);
+    msg!(
+        "Closed counter {}, {} lamports sent to {}",
+        account.key,
+        lamports,
+        destination.key
+    );
+    let context = Context {
+        counter: *account.key,
+        signer: *authority.key,
+        slot: Clock::get()?.slot,
+        roles: Roles::default(),
+    };
+    for permissions in rest
+        .iter()
+        .filter(|permissions| is_permissions(program_id, permissions))
+    {
+        let member = load_permissions_member(program_id, account, permissions)?;
+        close_account(permissions, destination)?;
+        msg!("Closed permissions of {}", member);
+        CounterEvent {
+            member: Some(member),
+            ..context.event(Operation::ClosePermissions, None, None)
+        }
+        .emit();
+    }
+    context.event(Operation::Close, Some(value), None).emit();
+    Ok(())
+}
+
+/// Deletes the permissions account of a member, sending its lamports to `destination`. The
+/// counter authority may close any of them and a member its own, which is the only way left
+/// once the counter itself is closed.
+///
+/// Accounts: `[] counter`, `[signer] authority` or member, `[writable] permissions`,
+/// `[writable] destination`, then `[signer]` multisig members when the authority is a
+/// multisig.
+fn process_close_permissions(program_id: &Pubkey, accounts: &[AccountInfo]) -> ProgramResult {
+    let accounts_iter = &mut accounts.iter();
+    let account = next_account_info(accounts_iter)?;
+    let authority = next_account_info(accounts_iter)?;
+    let permissions = next_account_info(accounts_iter)?;
+    let destination = next_account_info(accounts_iter)?;
+
+    let member = load_permissions_member(program_id, account, permissions)?;
+    if !authority.is_signer || *authority.key != member {
+        let counter = load_counter(program_id, account)?;
+        check_authority(program_id, &*counter, authority, accounts_iter.as_slice())?;
+    }
+    let lamports = close_account(permissions, destination)?;
+    msg!(
+        "Closed permissions of {} on {}, {} lamports sent to {}",
+        member,
+        account.key,
+        lamports,
+        destination.key
+    );
+    CounterEvent {
+        member: Some(member),
+        ..Context {
+            counter: *account.key,
+            signer: *authority.key,
+            slot: Clock::get()?.slot,
+            roles: Roles::default(),
+        }
+        .event(Operation::ClosePermissions, None, None)
+    }
+    .emit();
+    Ok(())
+}
+
+/// Checks that `permissions` is a permissions account of the counter that can be closed, and
+/// returns the member it belongs to.
+fn load_permissions_member(
+    program_id: &Pubkey,
+    account: &AccountInfo,
+    permissions: &AccountInfo,
+) -> Result<Pubkey, ProgramError> {
+    if permissions.owner != program_id {
+        return Err(CounterError::IncorrectAccountOwner.into());
+    }
+    let member = {
+        let data = permissions.try_borrow_data()?;
+        let entry = Permissions::load(&data)?;
+        if entry.counter() != account.key {
+            return Err(ProgramError::InvalidArgument);
+        }
+        *entry.member()
+    };
+    check_permissions_account(program_id, account, permissions, &member)?;
+    Ok(member)
+}
+
+/// Moves all lamports of `account` to `destination` and zeroes its data, returning the amount
+/// moved. The runtime deletes the account at the end of the transaction.
+fn close_account(account: &AccountInfo, destination: &AccountInfo) -> Result<u64, ProgramError> {
+    if account.key == destination.key {
+        return Err(ProgramError::InvalidArgument);
+    }
+    let lamports = account.lamports();
+    **destination.try_borrow_mut_lamports()? = destination
+        .lamports()
+        .checked_add(lamports)
+        .ok_or(ProgramError::ArithmeticOverflow)?;
+    **account.try_borrow_mut_lamports()? = 0;
+    account.try_borrow_mut_data()?.fill(0);
+    Ok(lamports)
+}
+
+/// Upgrades a counter written with an earlier layout to the current one, keeping its state.
+/// Legacy 4-byte counters had no authority, so only the upgrade authority of the program can
+/// claim them and it becomes their authority; later layouts keep theirs.
+///
+/// Accounts: `[writable] counter`, `[signer, writable] payer`, `[] system program`, then for
+/// legacy counters `[] program data` of this program and `[signer] upgrade authority`.
+fn process_migrate(program_id: &Pubkey, accounts: &[AccountInfo]) -> ProgramResult {
+    let accounts_iter = &mut accounts.iter();
+    let account = next_account_info(accounts_iter)?;
+    let payer = next_account_info(accounts_iter)?;
+    let system_program_info = next_account_info(accounts_iter)?;
+
+    if !payer.is_signer {
+        return Err(ProgramError::MissingRequiredSignature);
+    }
+    if !system_program::check_id(system_program_info.key) {
+        return Err(ProgramError::IncorrectProgramId);
+    }
+    if account.owner != program_id {
+        return Err(CounterError::IncorrectAccountOwner.into());
+    }
+    if !account.is_writable {
+        return Err(CounterError::AccountNotWritable.into());
+    }
+    let counter_account = if account.data_len() == LEGACY_LEN {
+        let program_data = next_account_info(accounts_iter)?;
+        let claimant = next_account_info(accounts_iter)?;
+        if !claimant.is_signer {
+            return Err(ProgramError::MissingRequiredSignature);
+        }
+        if upgrade_authority(program_id, program_data)? != Some(*claimant.key) {
+            msg!("Legacy counters can only be claimed by the program upgrade authority");
+            return Err(CounterError::Unauthorized.into());
         }
-        CounterInstructions::Reset => {
-            counter_account.counter = 0;
+        let mut legacy = [0u8; LEGACY_LEN];
+        legacy.copy_from_slice(&account.data.borrow());
+        CounterAccount {
+            authority: *claimant.key,
+            counter: CounterValue::U32(u32::from_le_bytes(legacy)),
+            overflow_policy: OverflowPolicy::default(),
+            underflow_policy: UnderflowPolicy::default(),
+            bounds: Bounds::default(),
+            pending_authority: None,
         }
-        CounterInstructions::Update(args) => {
-            counter_account.counter = args.value;
+    } else {
+        let data = account.data.borrow();
+        match CounterAccount::unpack_outdated(&data) {
+            Ok(counter_account) => counter_account,
+            Err(_) => {
+                // Fails with a descriptive error if the account is not a counter at all.
+                CounterAccount::unpack(&data)?;
+                return Err(ProgramError::AccountAlreadyInitialized);
+            }
         }
+    };
+
+    let required_lamports = Rent::get()?.minimum_balance(CounterAccount::LEN);
+    let shortfall = required_lamports.saturating_sub(account.lamports());
+    if shortfall > 0 {
+        invoke(
+            &system_instruction::transfer(payer.key, account.key, shortfall),
+            &[payer.clone(), account.clone(), system_program_info.clone()],
+        )?;
     }
+    account.realloc(CounterAccount::LEN, true)?;
 
-    counter_account.serialize(&mut &mut account.data.borrow_mut()[..])?;
+    let slot = Clock::get()?.slot;
+    let mut data = account.data.borrow_mut();
+    counter_account.pack(&mut data)?;
+    CounterData::load_mut(&mut data)?.set_nonce(slot);
+    msg!(
+        "Migrated counter {} with value {:?}",
+        account.key,
+        counter_account.counter
+    );
+    Context {
+        counter: *account.key,
+        signer: *payer.key,
+        slot,
+        roles: Roles::default(),
+    }
+    .event(
+        Operation::Migrate,
+        Some(counter_account.counter),
+        Some(counter_account.counter),
+    )
+    .emit();
     Ok(())
 }
 
+/// Reads the upgrade authority of the program from its program data account; `None` once the
+/// program is immutable.
+fn upgrade_authority(
+    program_id: &Pubkey,
+    program_data: &AccountInfo,
+) -> Result<Option<Pubkey>, ProgramError> {
+    let (address, _) =
+        Pubkey::find_program_address(&[program_id.as_ref()], &bpf_loader_upgradeable::id());
+    if *program_data.key != address || !bpf_loader_upgradeable::check_id(program_data.owner) {
+        return Err(ProgramError::InvalidAccountData);
+    }
+    // `UpgradeableLoaderState::ProgramData`: a u32 variant tag of 3, the deployment slot, then
+    // the authority as an `Option<Pubkey>`.
+    let data = program_data.try_borrow_data()?;
+    let metadata = data
+        .get(..UpgradeableLoaderState::size_of_programdata_metadata())
+        .ok_or(ProgramError::InvalidAccountData)?;
+    if metadata[..4] != 3u32.to_le_bytes() {
+        return Err(ProgramError::InvalidAccountData);
+    }
+    Ok(match metadata[12] {
+        0 => None,
+        _ => Some(Pubkey::new_from_array(metadata[13..].try_into().unwrap())),
+    })
+}
+
 #[cfg(test)]
 mod test {
     use super::*;
-    use solana_program::{clock::Epoch, lamports, pubkey::Pubkey};
-    use std::mem;
+    use crate::instructions::Op;
+    use solana_program::{clock::Epoch, entrypoint::SUCCESS, program_stubs, pubkey::Pubkey};
+    use std::{cell::RefCell, sync::Once};
+
+    thread_local! {
+        static RETURN_DATA: RefCell<Option<(Pubkey, Vec<u8>)>> = const { RefCell::new(None) };
+        static EVENTS: RefCell<Vec<CounterEvent>> = const { RefCell::new(vec![]) };
+    }
+
+    struct TestSyscallStubs;
+
+    impl program_stubs::SyscallStubs for TestSyscallStubs {
+        fn sol_get_rent_sysvar(&self, var_addr: *mut u8) -> u64 {
+            unsafe { *(var_addr as *mut Rent) = Rent::default() };
+            SUCCESS
+        }
+
+        fn sol_get_clock_sysvar(&self, var_addr: *mut u8) -> u64 {
+            unsafe { *(var_addr as *mut Clock) = Clock::default() };
+            SUCCESS
+        }
+
+        // The tests always run the program as `Pubkey::default()`.
+        fn sol_set_return_data(&self, data: &[u8]) {
+            RETURN_DATA.with(|cell| *cell.borrow_mut() = Some((Pubkey::default(), data.to_vec())));
+        }
+
+        fn sol_get_return_data(&self) -> Option<(Pubkey, Vec<u8>)> {
+            RETURN_DATA.with(|cell| cell.borrow().clone())
+        }
+
+        fn sol_log_data(&self, fields: &[&[u8]]) {
+            EVENTS.with(|cell| {
+                let events = fields
+                    .iter()
+                    .filter_map(|field| CounterEvent::from_bytes(field));
+                cell.borrow_mut().extend(events)
+            });
+        }
+    }
+
+    /// Makes `Rent::get()`, `Clock::get()`, return data and events work outside the runtime.
+    fn setup() {
+        static STUBS: Once = Once::new();
+        STUBS.call_once(|| {
+            program_stubs::set_syscall_stubs(Box::new(TestSyscallStubs));
+        });
+    }
 
     struct StubIncrementInputProvider;
     struct StubDecrementInputProvider;
@@ -96,11 +1014,26 @@ mod test {
 
     #[test]
     fn test_counter() {
+        setup();
         let program_id = Pubkey::default();
         let key = Pubkey::default();
-        let mut lamports = 0;
-        let mut data = vec![0; mem::size_of::<u32>()];
+        let mut lamports = Rent::default().minimum_balance(CounterAccount::LEN);
+        let mut data = vec![0; CounterAccount::LEN];
         let owner = Pubkey::default();
+        let authority_key = Pubkey::new_unique();
+        let mut authority_lamports = 0;
+        let mut authority_data = vec![];
+
+        CounterAccount {
+            authority: authority_key,
+            counter: CounterValue::U32(0),
+            overflow_policy: OverflowPolicy::Error,
+            underflow_policy: UnderflowPolicy::Saturate,
+            bounds: Bounds::default(),
+            pending_authority: None,
+        }
+        .pack(&mut data)
+        .unwrap();
 
         let account = AccountInfo::new(
             &key,
@@ -112,8 +1045,18 @@ mod test {
             false,
             Epoch::default(),
         );
+        let authority = AccountInfo::new(
+            &authority_key,
+            true,
+            false,
+            &mut authority_lamports,
+            &mut authority_data,
+            &owner,
+            false,
+            Epoch::default(),
+        );
 
-        let accounts = vec![account];
+        let accounts = vec![account, authority];
 
         let mut increment_instruction_data = vec![0];
         let mut decrement_instruction_data = vec![1];
@@ -128,13 +1071,14 @@ mod test {
                 return; // or handle the error in some appropriate way
             }
         };
-        increment_instruction_data.extend_from_slice(&parsed_increment_value.to_le_bytes());
+        increment_instruction_data
+            .extend_from_slice(&borsh::to_vec(&CounterValue::U32(parsed_increment_value)).unwrap());
         process_instruction(&program_id, &accounts, &increment_instruction_data).unwrap();
         assert_eq!(
-            CounterAccount::try_from_slice(&accounts[0].data.borrow())
+            CounterAccount::unpack(&accounts[0].data.borrow())
                 .unwrap()
                 .counter,
-            20
+            CounterValue::U32(20)
         );
 
         let parsed_decrement_value: u32 = match StubDecrementInputProvider.get_input().parse() {
@@ -144,13 +1088,14 @@ mod test {
                 return; // or handle the error in some appropriate way
             }
         };
-        decrement_instruction_data.extend_from_slice(&parsed_decrement_value.to_le_bytes());
+        decrement_instruction_data
+            .extend_from_slice(&borsh::to_vec(&CounterValue::U32(parsed_decrement_value)).unwrap());
         process_instruction(&program_id, &accounts, &decrement_instruction_data).unwrap();
         assert_eq!(
-            CounterAccount::try_from_slice(&accounts[0].data.borrow())
+            CounterAccount::unpack(&accounts[0].data.borrow())
                 .unwrap()
                 .counter,
-            10
+            CounterValue::U32(10)
         );
 
         let parsed_decrement_value_gt_current_value: u32 = match StubDecrementInputProviderGreaterThanCurrentValue.get_input().parse() {
@@ -160,32 +1105,819 @@ mod test {
                 return; // or handle the error in some appropriate way
             }
         };
-        decrement_instruction_data_gt_current_value.extend_from_slice(&parsed_decrement_value_gt_current_value.to_le_bytes());
+        decrement_instruction_data_gt_current_value.extend_from_slice(&borsh::to_vec(&CounterValue::U32(parsed_decrement_value_gt_current_value)).unwrap());
         process_instruction(&program_id, &accounts, &decrement_instruction_data_gt_current_value).unwrap();
         assert_eq!(
-            CounterAccount::try_from_slice(&accounts[0].data.borrow())
+            CounterAccount::unpack(&accounts[0].data.borrow())
                 .unwrap()
                 .counter,
-            0
+            CounterValue::U32(0)
         );
 
-        let update_value = 33u32;
-        update_instruction_data.extend_from_slice(&update_value.to_le_bytes());
+        let update_value = CounterValue::U32(33);
+        update_instruction_data.extend_from_slice(&borsh::to_vec(&update_value).unwrap());
 
         process_instruction(&program_id, &accounts, &update_instruction_data).unwrap();
         assert_eq!(
-            CounterAccount::try_from_slice(&accounts[0].data.borrow())
+            CounterAccount::unpack(&accounts[0].data.borrow())
                 .unwrap()
                 .counter,
-            33
+            CounterValue::U32(33)
         );
 
         process_instruction(&program_id, &accounts, &reset_instruction_data).unwrap();
         assert_eq!(
-            CounterAccount::try_from_slice(&accounts[0].data.borrow())
+            CounterAccount::unpack(&accounts[0].data.borrow())
+                .unwrap()
+                .counter,
+            CounterValue::U32(0)
+        );
+    }
+
+    #[test]
+    fn test_counter_rejects_foreign_signer() {
+        setup();
+        let program_id = Pubkey::default();
+        let key = Pubkey::default();
+        let mut lamports = Rent::default().minimum_balance(CounterAccount::LEN);
+        let mut data = vec![0; CounterAccount::LEN];
+        let owner = Pubkey::default();
+        let authority_key = Pubkey::new_unique();
+        let intruder_key = Pubkey::new_unique();
+        let mut intruder_lamports = 0;
+        let mut intruder_data = vec![];
+
+        CounterAccount {
+            authority: authority_key,
+            counter: CounterValue::U32(5),
+            overflow_policy: OverflowPolicy::Error,
+            underflow_policy: UnderflowPolicy::Saturate,
+            bounds: Bounds::default(),
+            pending_authority: None,
+        }
+        .pack(&mut data)
+        .unwrap();
+
+        let account = AccountInfo::new(
+            &key,
+            false,
+            true,
+            &mut lamports,
+            &mut data,
+            &owner,
+            false,
+            Epoch::default(),
+        );
+        let intruder = AccountInfo::new(
+            &intruder_key,
+            true,
+            false,
+            &mut intruder_lamports,
+            &mut intruder_data,
+            &owner,
+            false,
+            Epoch::default(),
+        );
+        let accounts = vec![account, intruder];
+
+        assert_eq!(
+            process_instruction(&program_id, &accounts, &[3]),
+            Err(CounterError::Unauthorized.into())
+        );
+        assert_eq!(
+            CounterAccount::unpack(&accounts[0].data.borrow())
                 .unwrap()
                 .counter,
-            0
+            CounterValue::U32(5)
+        );
+    }
+
+    fn increment_with_policy(
+        overflow_policy: OverflowPolicy,
+        start: CounterValue,
+        value: CounterValue,
+    ) -> Result<CounterValue, ProgramError> {
+        setup();
+        let program_id = Pubkey::default();
+        let key = Pubkey::default();
+        let mut lamports = Rent::default().minimum_balance(CounterAccount::LEN);
+        let mut data = vec![0; CounterAccount::LEN];
+        let owner = Pubkey::default();
+        let authority_key = Pubkey::new_unique();
+        let mut authority_lamports = 0;
+        let mut authority_data = vec![];
+
+        CounterAccount {
+            authority: authority_key,
+            counter: start,
+            overflow_policy,
+            underflow_policy: UnderflowPolicy::Saturate,
+            bounds: Bounds::default(),
+            pending_authority: None,
+        }
+        .pack(&mut data)
+        .unwrap();
+
+        let account = AccountInfo::new(
+            &key,
+            false,
+            true,
+            &mut lamports,
+            &mut data,
+            &owner,
+            false,
+            Epoch::default(),
+        );
+        let authority = AccountInfo::new(
+            &authority_key,
+            true,
+            false,
+            &mut authority_lamports,
+            &mut authority_data,
+            &owner,
+            false,
+            Epoch::default(),
+        );
+        let accounts = vec![account, authority];
+
+        let mut increment_instruction_data = vec![0];
+        increment_instruction_data.extend_from_slice(&borsh::to_vec(&value).unwrap());
+        process_instruction(&program_id, &accounts, &increment_instruction_data)?;
+        let counter = CounterAccount::unpack(&accounts[0].data.borrow())
+            .unwrap()
+            .counter;
+        Ok(counter)
+    }
+
+    #[test]
+    fn test_increment_overflow_policy() {
+        use CounterValue::{I64, U32, U64};
+
+        let cases = [
+            (OverflowPolicy::Error, U32(u32::MAX - 1), U32(2), None),
+            (
+                OverflowPolicy::Saturate,
+                U32(u32::MAX - 1),
+                U32(2),
+                Some(U32(u32::MAX)),
+            ),
+            (
+                OverflowPolicy::Wrap,
+                U32(u32::MAX - 1),
+                U32(2),
+                Some(U32(0)),
+            ),
+            (OverflowPolicy::Error, U64(u64::MAX), U64(1), None),
+            (
+                OverflowPolicy::Saturate,
+                U64(u64::MAX),
+                U64(1),
+                Some(U64(u64::MAX)),
+            ),
+            (OverflowPolicy::Wrap, U64(u64::MAX), U64(1), Some(U64(0))),
+            (OverflowPolicy::Error, I64(i64::MAX), I64(1), None),
+            (
+                OverflowPolicy::Saturate,
+                I64(i64::MAX),
+                I64(1),
+                Some(I64(i64::MAX)),
+            ),
+            (
+                OverflowPolicy::Wrap,
+                I64(i64::MAX),
+                I64(1),
+                Some(I64(i64::MIN)),
+            ),
+            (OverflowPolicy::Error, U64(40), U64(2), Some(U64(42))),
+        ];
+        for (policy, start, value, expected) in cases {
+            assert_eq!(
+                increment_with_policy(policy, start, value),
+                expected.ok_or(CounterError::Overflow.into()),
+                "{:?} {:?} + {:?}",
+                policy,
+                start,
+                value
+            );
+        }
+        assert_eq!(
+            increment_with_policy(OverflowPolicy::Error, U64(1), U32(1)),
+            Err(CounterError::KindMismatch.into())
+        );
+        assert_eq!(
+            increment_with_policy(OverflowPolicy::Error, I64(1), I64(-1)),
+            Err(CounterError::NegativeAmount.into())
+        );
+    }
+
+    #[test]
+    fn test_decrement_underflow_policy() {
+        let program_id = Pubkey::default();
+        let key = Pubkey::default();
+        let mut lamports = Rent::default().minimum_balance(CounterAccount::LEN);
+        let mut data = vec![0; CounterAccount::LEN];
+        let owner = Pubkey::default();
+        let authority_key = Pubkey::new_unique();
+        let mut authority_lamports = 0;
+        let mut authority_data = vec![];
+
+        CounterAccount {
+            authority: authority_key,
+            counter: CounterValue::U32(5),
+            overflow_policy: OverflowPolicy::Error,
+            underflow_policy: UnderflowPolicy::Saturate,
+            bounds: Bounds::default(),
+            pending_authority: None,
+        }
+        .pack(&mut data)
+        .unwrap();
+
+        let account = AccountInfo::new(
+            &key,
+            false,
+            true,
+            &mut lamports,
+            &mut data,
+            &owner,
+            false,
+            Epoch::default(),
+        );
+        let authority = AccountInfo::new(
+            &authority_key,
+            true,
+            false,
+            &mut authority_lamports,
+            &mut authority_data,
+            &owner,
+            false,
+            Epoch::default(),
+        );
+        let accounts = vec![account, authority];
+
+        setup();
+        let mut try_decrement_instruction_data = vec![5];
+        try_decrement_instruction_data
+            .extend_from_slice(&borsh::to_vec(&CounterValue::U32(6)).unwrap());
+        assert_eq!(
+            process_instruction(&program_id, &accounts, &try_decrement_instruction_data),
+            Err(CounterError::Underflow.into())
+        );
+        assert_eq!(
+            CounterAccount::unpack(&accounts[0].data.borrow())
+                .unwrap()
+                .counter,
+            CounterValue::U32(5)
+        );
+    }
+
+    #[test]
+    fn test_close_counter() {
+        setup();
+        let program_id = Pubkey::default();
+        let key = Pubkey::new_unique();
+        let mut lamports = Rent::default().minimum_balance(CounterAccount::LEN);
+        let mut data = vec![0; CounterAccount::LEN];
+        let owner = Pubkey::default();
+        let authority_key = Pubkey::new_unique();
+        let mut authority_lamports = 0;
+        let mut authority_data = vec![];
+        let destination_key = Pubkey::new_unique();
+        let mut destination_lamports = 5;
+        let mut destination_data = vec![];
+
+        CounterAccount {
+            authority: authority_key,
+            counter: CounterValue::U64(3),
+            overflow_policy: OverflowPolicy::Error,
+            underflow_policy: UnderflowPolicy::Saturate,
+            bounds: Bounds::default(),
+            pending_authority: None,
+        }
+        .pack(&mut data)
+        .unwrap();
+
+        let account = AccountInfo::new(
+            &key,
+            false,
+            true,
+            &mut lamports,
+            &mut data,
+            &owner,
+            false,
+            Epoch::default(),
+        );
+        let authority = AccountInfo::new(
+            &authority_key,
+            true,
+            false,
+            &mut authority_lamports,
+            &mut authority_data,
+            &owner,
+            false,
+            Epoch::default(),
+        );
+        let destination = AccountInfo::new(
+            &destination_key,
+            false,
+            true,
+            &mut destination_lamports,
+            &mut destination_data,
+            &owner,
+            false,
+            Epoch::default(),
+        );
+        let accounts = vec![account, authority, destination];
+
+        process_instruction(&program_id, &accounts, &[7]).unwrap();
+        assert_eq!(accounts[0].lamports(), 0);
+        assert_eq!(
+            accounts[2].lamports(),
+            Rent::default().minimum_balance(CounterAccount::LEN) + 5
+        );
+        assert_eq!(accounts[0].data.borrow()[..8], CLOSED_DISCRIMINATOR);
+        assert!(accounts[0].data.borrow()[8..].iter().all(|&byte| byte == 0));
+
+        assert_eq!(
+            process_instruction(&program_id, &accounts, &[3]),
+            Err(CounterError::AccountClosed.into())
+        );
+        assert_eq!(
+            process_instruction(&program_id, &accounts, &[7]),
+            Err(CounterError::AccountClosed.into())
+        );
+    }
+
+    #[test]
+    fn test_close_permissions_with_counter() {
+        setup();
+        let program_id = Pubkey::default();
+        let key = Pubkey::new_unique();
+        let mut lamports = Rent::default().minimum_balance(CounterAccount::LEN);
+        let mut data = vec![0; CounterAccount::LEN];
+        let owner = Pubkey::default();
+        let authority_key = Pubkey::new_unique();
+        let mut authority_lamports = 0;
+        let mut authority_data = vec![];
+        let member = Pubkey::new_unique();
+        let (permissions_key, _) = find_permissions_address(&program_id, &key, &member);
+        let mut permissions_lamports = Rent::default().minimum_balance(Permissions::LEN);
+        let mut permissions_data = vec![0; Permissions::LEN];
+        let destination_key = Pubkey::new_unique();
+        let mut destination_lamports = 0;
+        let mut destination_data = vec![];
+
+        CounterAccount {
+            authority: authority_key,
+            counter: CounterValue::U64(3),
+            overflow_policy: OverflowPolicy::Error,
+            underflow_policy: UnderflowPolicy::Saturate,
+            bounds: Bounds::default(),
+            pending_authority: None,
+        }
+        .pack(&mut data)
+        .unwrap();
+        Permissions::new(key, member, 0)
+            .pack(&mut permissions_data)
+            .unwrap();
+
+        let account = AccountInfo::new(
+            &key,
+            false,
+            true,
+            &mut lamports,
+            &mut data,
+            &owner,
+            false,
+            Epoch::default(),
+        );
+        let authority = AccountInfo::new(
+            &authority_key,
+            true,
+            false,
+            &mut authority_lamports,
+            &mut authority_data,
+            &owner,
+            false,
+            Epoch::default(),
+        );
+        let destination = AccountInfo::new(
+            &destination_key,
+            false,
+            true,
+            &mut destination_lamports,
+            &mut destination_data,
+            &owner,
+            false,
+            Epoch::default(),
+        );
+        let permissions = AccountInfo::new(
+            &permissions_key,
+            false,
+            true,
+            &mut permissions_lamports,
+            &mut permissions_data,
+            &owner,
+            false,
+            Epoch::default(),
+        );
+        let accounts = vec![account, authority, destination, permissions];
+
+        EVENTS.with(|cell| cell.borrow_mut().clear());
+        process_instruction(&program_id, &accounts, &[7]).unwrap();
+        assert_eq!(accounts[3].lamports(), 0);
+        let events = EVENTS.with(|cell| cell.take());
+        let operations: Vec<Operation> = events.iter().map(|event| event.operation).collect();
+        assert_eq!(operations, [Operation::ClosePermissions, Operation::Close]);
+        assert_eq!(events[0].member, Some(member));
+        assert_eq!(events[0].role, None);
+    }
+
+    #[test]
+    fn test_counter_account_validation() {
+        setup();
+        let program_id = Pubkey::new_unique();
+        let key = Pubkey::new_unique();
+        let authority_key = Pubkey::new_unique();
+        let foreign_owner = Pubkey::new_unique();
+        let rent_exempt = Rent::default().minimum_balance(CounterAccount::LEN);
+
+        let cases = [
+            (
+                foreign_owner,
+                true,
+                rent_exempt,
+                CounterAccount::LEN,
+                CounterError::IncorrectAccountOwner,
+            ),
+            (
+                program_id,
+                false,
+                rent_exempt,
+                CounterAccount::LEN,
+                CounterError::AccountNotWritable,
+            ),
+            (
+                program_id,
+                true,
+                rent_exempt,
+                CounterAccount::LEN + 1,
+                CounterError::InvalidAccountDataLength,
+            ),
+            (
+                program_id,
+                true,
+                rent_exempt,
+                CounterAccount::LEN - 1,
+                CounterError::InvalidAccountDataLength,
+            ),
+            (
+                program_id,
+                true,
+                rent_exempt,
+                LEGACY_LEN,
+                CounterError::LegacyAccount,
+            ),
+            (
+                program_id,
+                true,
+                rent_exempt - 1,
+                CounterAccount::LEN,
+                CounterError::AccountNotRentExempt,
+            ),
+        ];
+        for (owner, is_writable, mut lamports, len, expected) in cases {
+            let mut data = vec![0; CounterAccount::LEN];
+            let mut authority_lamports = 0;
+            let mut authority_data = vec![];
+            CounterAccount {
+                authority: authority_key,
+                counter: CounterValue::U32(1),
+                overflow_policy: OverflowPolicy::Error,
+                underflow_policy: UnderflowPolicy::Saturate,
+                bounds: Bounds::default(),
+                pending_authority: None,
+            }
+            .pack(&mut data)
+            .unwrap();
+            data.resize(len, 0);
+
+            let account = AccountInfo::new(
+                &key,
+                false,
+                is_writable,
+                &mut lamports,
+                &mut data,
+                &owner,
+                false,
+                Epoch::default(),
+            );
+            let authority = AccountInfo::new(
+                &authority_key,
+                true,
+                false,
+                &mut authority_lamports,
+                &mut authority_data,
+                &owner,
+                false,
+                Epoch::default(),
+            );
+            assert_eq!(
+                process_instruction(&program_id, &[account, authority], &[3]),
+                Err(expected.into())
+            );
+        }
+    }
+
+    #[test]
+    fn test_compare_and_swap() {
+        setup();
+        let program_id = Pubkey::default();
+        let key = Pubkey::default();
+        let mut lamports = Rent::default().minimum_balance(CounterAccount::LEN);
+        let mut data = vec![0; CounterAccount::LEN];
+        let owner = Pubkey::default();
+        let authority_key = Pubkey::new_unique();
+        let mut authority_lamports = 0;
+        let mut authority_data = vec![];
+
+        CounterAccount {
+            authority: authority_key,
+            counter: CounterValue::U64(7),
+            overflow_policy: OverflowPolicy::Error,
+            underflow_policy: UnderflowPolicy::Saturate,
+            bounds: Bounds::default(),
+            pending_authority: None,
+        }
+        .pack(&mut data)
+        .unwrap();
+
+        let account = AccountInfo::new(
+            &key,
+            false,
+            true,
+            &mut lamports,
+            &mut data,
+            &owner,
+            false,
+            Epoch::default(),
+        );
+        let authority = AccountInfo::new(
+            &authority_key,
+            true,
+            false,
+            &mut authority_lamports,
+            &mut authority_data,
+            &owner,
+            false,
+            Epoch::default(),
+        );
+        let accounts = vec![account, authority];
+
+        let compare_and_swap_data = |expected: u64, new: u64| {
+            let mut data = vec![9];
+            data.extend_from_slice(&borsh::to_vec(&CounterValue::U64(expected)).unwrap());
+            data.extend_from_slice(&borsh::to_vec(&CounterValue::U64(new)).unwrap());
+            data
+        };
+
+        assert_eq!(
+            process_instruction(&program_id, &accounts, &compare_and_swap_data(6, 8)),
+            Err(CounterError::CompareMismatch.into())
+        );
+        process_instruction(&program_id, &accounts, &compare_and_swap_data(7, 8)).unwrap();
+        assert_eq!(
+            CounterAccount::unpack(&accounts[0].data.borrow())
+                .unwrap()
+                .counter,
+            CounterValue::U64(8)
+        );
+        assert_eq!(
+            process_instruction(&program_id, &accounts, &compare_and_swap_data(7, 9)),
+            Err(CounterError::CompareMismatch.into())
+        );
+    }
+
+    #[test]
+    fn test_batch_is_atomic() {
+        setup();
+        let program_id = Pubkey::default();
+        let key = Pubkey::default();
+        let mut lamports = Rent::default().minimum_balance(CounterAccount::LEN);
+        let mut data = vec![0; CounterAccount::LEN];
+        let owner = Pubkey::default();
+        let authority_key = Pubkey::new_unique();
+        let mut authority_lamports = 0;
+        let mut authority_data = vec![];
+
+        CounterAccount {
+            authority: authority_key,
+            counter: CounterValue::U32(1),
+            overflow_policy: OverflowPolicy::Error,
+            underflow_policy: UnderflowPolicy::Saturate,
+            bounds: Bounds::default(),
+            pending_authority: None,
+        }
+        .pack(&mut data)
+        .unwrap();
+
+        let account = AccountInfo::new(
+            &key,
+            false,
+            true,
+            &mut lamports,
+            &mut data,
+            &owner,
+            false,
+            Epoch::default(),
+        );
+        let authority = AccountInfo::new(
+            &authority_key,
+            true,
+            false,
+            &mut authority_lamports,
+            &mut authority_data,
+            &owner,
+            false,
+            Epoch::default(),
+        );
+        let accounts = vec![account, authority];
+
+        let batch_data = |ops: Vec<Op>| {
+            let mut data = vec![10];
+            data.extend_from_slice(&borsh::to_vec(&ops).unwrap());
+            data
+        };
+
+        let failing_batch = batch_data(vec![
+            Op::Increment(CounterValue::U32(5)),
+            Op::TryDecrement(CounterValue::U32(100)),
+        ]);
+        assert_eq!(
+            process_instruction(&program_id, &accounts, &failing_batch),
+            Err(CounterError::Underflow.into())
+        );
+        assert_eq!(
+            CounterAccount::unpack(&accounts[0].data.borrow())
+                .unwrap()
+                .counter,
+            CounterValue::U32(1)
+        );
+
+        let batch = batch_data(vec![
+            Op::Increment(CounterValue::U32(5)),
+            Op::Decrement(CounterValue::U32(2)),
+            Op::CompareAndSwap(instructions::CompareAndSwapArgs {
+                expected: CounterValue::U32(4),
+                new: CounterValue::U32(10),
+            }),
+        ]);
+        process_instruction(&program_id, &accounts, &batch).unwrap();
+        assert_eq!(
+            CounterAccount::unpack(&accounts[0].data.borrow())
+                .unwrap()
+                .counter,
+            CounterValue::U32(10)
+        );
+    }
+
+    #[test]
+    fn test_fan_out_reports_failing_counter() {
+        setup();
+        let program_id = Pubkey::new_unique();
+        let authority_key = Pubkey::new_unique();
+        let foreign_owner = Pubkey::new_unique();
+        let keys = [Pubkey::new_unique(), Pubkey::new_unique()];
+        let mut lamports = [Rent::default().minimum_balance(CounterAccount::LEN); 2];
+        let mut data = [vec![0; CounterAccount::LEN], vec![0; CounterAccount::LEN]];
+        let mut authority_lamports = 0;
+        let mut authority_data = vec![];
+
+        for (index, data) in data.iter_mut().enumerate() {
+            CounterAccount {
+                authority: authority_key,
+                counter: CounterValue::U32(index as u32),
+                overflow_policy: OverflowPolicy::Error,
+                underflow_policy: UnderflowPolicy::Saturate,
+                bounds: Bounds::default(),
+                pending_authority: None,
+            }
+            .pack(data)
+            .unwrap();
+        }
+
+        let authority = AccountInfo::new(
+            &authority_key,
+            true,
+            false,
+            &mut authority_lamports,
+            &mut authority_data,
+            &foreign_owner,
+            false,
+            Epoch::default(),
+        );
+        let [first_lamports, second_lamports] = &mut lamports;
+        let [first_data, second_data] = &mut data;
+        let first = AccountInfo::new(
+            &keys[0],
+            false,
+            true,
+            first_lamports,
+            first_data,
+            &program_id,
+            false,
+            Epoch::default(),
+        );
+        let second = AccountInfo::new(
+            &keys[1],
+            false,
+            true,
+            second_lamports,
+            second_data,
+            &program_id,
+            false,
+            Epoch::default(),
+        );
+        let mut accounts = vec![authority, first, second];
+
+        let fan_out = |signers| {
+            CounterInstructions::FanOut(FanOutArgs {
+                op: Op::Increment(CounterValue::U32(10)),
+                signers,
+            })
+            .pack()
+        };
+        let fan_out_data = fan_out(0);
+        process_instruction(&program_id, &accounts, &fan_out_data).unwrap();
+        for (index, account) in accounts[1..].iter().enumerate() {
+            assert_eq!(
+                CounterAccount::unpack(&account.data.borrow())
+                    .unwrap()
+                    .counter,
+                CounterValue::U32(index as u32 + 10)
+            );
+        }
+
+        accounts[2].owner = &foreign_owner;
+        assert_eq!(
+            process_instruction(&program_id, &accounts, &fan_out_data),
+            Err(CounterError::IncorrectAccountOwner.into())
+        );
+        let (_, failed_at) = get_return_data().unwrap();
+        assert_eq!(failed_at, 2u32.to_le_bytes());
+        assert_eq!(
+            process_instruction(&program_id, &accounts[..1], &fan_out_data),
+            Err(ProgramError::NotEnoughAccountKeys)
+        );
+        assert_eq!(
+            process_instruction(&program_id, &accounts, &fan_out(3)),
+            Err(ProgramError::NotEnoughAccountKeys)
+        );
+    }
+
+    #[test]
+    fn test_get_returns_counter() {
+        setup();
+        let program_id = Pubkey::default();
+        let key = Pubkey::new_unique();
+        let mut lamports = Rent::default().minimum_balance(CounterAccount::LEN);
+        let mut data = vec![0; CounterAccount::LEN];
+        let owner = Pubkey::default();
+        let authority_key = Pubkey::new_unique();
+
+        CounterAccount {
+            authority: authority_key,
+            counter: CounterValue::I64(-4),
+            overflow_policy: OverflowPolicy::Error,
+            underflow_policy: UnderflowPolicy::Saturate,
+            bounds: Bounds::default(),
+            pending_authority: None,
+        }
+        .pack(&mut data)
+        .unwrap();
+
+        // Read-only access is enough, and no signer is needed.
+        let account = AccountInfo::new(
+            &key,
+            false,
+            false,
+            &mut lamports,
+            &mut data,
+            &owner,
+            false,
+            Epoch::default(),
+        );
+
+        assert_eq!(
+            get_counter_return_data(&program_id).err(),
+            Some(CounterError::MissingReturnData.into())
+        );
+        process_instruction(&program_id, &[account], &[12]).unwrap();
+        let counter_account = get_counter_return_data(&program_id).unwrap();
+        assert_eq!(counter_account.counter, CounterValue::I64(-4));
+        assert_eq!(counter_account.authority, authority_key);
+        assert_eq!(
+            get_counter_return_data(&Pubkey::new_unique()).err(),
+            Some(CounterError::MissingReturnData.into())
         );
     }
 }
@@ -0,0 +1,1152 @@
+use crate::error::CounterError;
+use borsh::{BorshDeserialize, BorshSerialize};
+use bytemuck::{Pod, Zeroable};
+use num_derive::FromPrimitive;
+use num_traits::FromPrimitive;
+use solana_program::{program_error::ProgramError, pubkey::Pubkey};
+
+/// First bytes of every counter account, used to tell it apart from any other account.
+#[cfg(not(feature = "anchor"))]
+pub const COUNTER_DISCRIMINATOR: [u8; 8] = *b"counter\0";
+
+/// First bytes of every counter account, in the form Anchor clients expect. Accounts written
+/// by a build without the `anchor` feature are not readable by one with it, and vice versa.
+#[cfg(feature = "anchor")]
+pub const COUNTER_DISCRIMINATOR: [u8; 8] = crate::anchor::ACCOUNT_DISCRIMINATOR;
+
+/// First bytes of every multisig account.
+#[cfg(not(feature = "anchor"))]
+pub const MULTISIG_DISCRIMINATOR: [u8; 8] = *b"multisig";
+
+/// First bytes of every multisig account, in the form Anchor clients expect.
+#[cfg(feature = "anchor")]
+pub const MULTISIG_DISCRIMINATOR: [u8; 8] = crate::anchor::MULTISIG_DISCRIMINATOR;
+
+/// First bytes of every permissions account.
+#[cfg(not(feature = "anchor"))]
+pub const PERMISSIONS_DISCRIMINATOR: [u8; 8] = *b"permissn";
+
+/// First bytes of every permissions account, in the form Anchor clients expect.
+#[cfg(feature = "anchor")]
+pub const PERMISSIONS_DISCRIMINATOR: [u8; 8] = crate::anchor::PERMISSIONS_DISCRIMINATOR;
+
+/// Most members a multisig can have.
+pub const MAX_SIGNERS: usize = 11;
+
+/// Written over the discriminator when a counter is closed, so the account can never be read
+/// as a counter again.
+pub const CLOSED_DISCRIMINATOR: [u8; 8] = [0xff; 8];
+
+/// Layout version written after the discriminator. Bump it whenever `CounterData` changes and
+/// teach `Migrate` how to upgrade the previous layout.
+pub const COUNTER_VERSION: u8 = 3;
+
+/// Version of the first layout with a header: a borsh-encoded authority, `u32` value and
+/// policies.
+pub const U32_LAYOUT_VERSION: u8 = 1;
+
+/// Version of the borsh layout with a kind-tagged value but no bounds.
+pub const KIND_LAYOUT_VERSION: u8 = 2;
+
+/// Discriminator plus version byte.
+pub const HEADER_LEN: usize = 8 + 1;
+
+/// Size of the accounts created before the header existed: a bare borsh `u32`.
+pub const LEGACY_LEN: usize = 4;
+
+/// Integer type of a counter, chosen when the counter is initialized.
+#[derive(
+    Clone, Copy, Debug, Default, PartialEq, Eq, BorshDeserialize, BorshSerialize, FromPrimitive,
+)]
+pub enum CounterKind {
+    /// The original 32-bit unsigned counter.
+    #[default]
+    U32,
+    U64,
+    I64,
+}
+
+impl CounterKind {
+    pub fn min(self) -> i128 {
+        match self {
+            Self::U32 | Self::U64 => 0,
+            Self::I64 => i64::MIN.into(),
+        }
+    }
+
+    pub fn max(self) -> i128 {
+        match self {
+            Self::U32 => u32::MAX.into(),
+            Self::U64 => u64::MAX.into(),
+            Self::I64 => i64::MAX.into(),
+        }
+    }
+}
+
+/// A counter value or instruction amount. The borsh encoding is a kind tag followed by the
+/// little-endian integer of that kind, so every value on the wire states its own width.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, BorshDeserialize, BorshSerialize)]
+pub enum CounterValue {
+    U32(u32),
+    U64(u64),
+    I64(i64),
+}
+
+impl CounterValue {
+    /// Largest borsh encoding of a value.
+    pub const LEN: usize = 1 + 8;
+
+    pub fn zero(kind: CounterKind) -> Self {
+        match kind {
+            CounterKind::U32 => Self::U32(0),
+            CounterKind::U64 => Self::U64(0),
+            CounterKind::I64 => Self::I64(0),
+        }
+    }
+
+    pub fn kind(self) -> CounterKind {
+        match self {
+            Self::U32(_) => CounterKind::U32,
+            Self::U64(_) => CounterKind::U64,
+            Self::I64(_) => CounterKind::I64,
+        }
+    }
+
+    pub fn to_i128(self) -> i128 {
+        match self {
+            Self::U32(value) => value.into(),
+            Self::U64(value) => value.into(),
+            Self::I64(value) => value.into(),
+        }
+    }
+
+    /// Converts back to a value of `kind`, or `None` if it does not fit.
+    pub fn from_i128(kind: CounterKind, value: i128) -> Option<Self> {
+        Some(match kind {
+            CounterKind::U32 => Self::U32(value.try_into().ok()?),
+            CounterKind::U64 => Self::U64(value.try_into().ok()?),
+            CounterKind::I64 => Self::I64(value.try_into().ok()?),
+        })
+    }
+
+    /// The value as stored in `CounterData`: zero-extended for unsigned kinds, two's complement
+    /// for `I64`.
+    fn to_bits(self) -> u64 {
+        match self {
+            Self::U32(value) => value.into(),
+            Self::U64(value) => value,
+            Self::I64(value) => value as u64,
+        }
+    }
+
+    fn from_bits(kind: CounterKind, bits: u64) -> Self {
+        match kind {
+            CounterKind::U32 => Self::U32(bits as u32),
+            CounterKind::U64 => Self::U64(bits),
+            CounterKind::I64 => Self::I64(bits as i64),
+        }
+    }
+
+    /// Converts back to a value of `kind`, truncating like an integer cast.
+    fn wrapping_from_i128(kind: CounterKind, value: i128) -> Self {
+        match kind {
+            CounterKind::U32 => Self::U32(value as u32),
+            CounterKind::U64 => Self::U64(value as u64),
+            CounterKind::I64 => Self::I64(value as i64),
+        }
+    }
+}
+
+/// What `Increment` does when the result does not fit in the counter.
+#[derive(
+    Clone, Copy, Debug, Default, PartialEq, Eq, BorshDeserialize, BorshSerialize, FromPrimitive,
+)]
+pub enum OverflowPolicy {
+    /// Fail the instruction with `CounterError::Overflow`.
+    #[default]
+    Error,
+    /// Stop at the largest value of the counter kind.
+    Saturate,
+    /// Wrap around like the integer type of the counter kind.
+    Wrap,
+}
+
+/// What `Decrement` does when the result would go below the smallest value of the counter.
+#[derive(
+    Clone, Copy, Debug, Default, PartialEq, Eq, BorshDeserialize, BorshSerialize, FromPrimitive,
+)]
+pub enum UnderflowPolicy {
+    /// Stop at the smallest value of the counter kind: 0, or `i64::MIN` for signed counters.
+    #[default]
+    Saturate,
+    /// Fail the instruction with `CounterError::Underflow`.
+    Error,
+}
+
+/// What happens when an operation would move the counter outside its `Bounds`.
+#[derive(
+    Clone, Copy, Debug, Default, PartialEq, Eq, BorshDeserialize, BorshSerialize, FromPrimitive,
+)]
+pub enum BoundsPolicy {
+    /// Fail the instruction with `CounterError::OutOfBounds`.
+    #[default]
+    Reject,
+    /// Store the nearest bound instead.
+    Clamp,
+}
+
+/// Optional range the counter has to stay in, applied after the overflow and underflow
+/// policies.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, BorshDeserialize, BorshSerialize)]
+pub struct Bounds {
+    pub min: Option<CounterValue>,
+    pub max: Option<CounterValue>,
+    pub policy: BoundsPolicy,
+}
+
+impl Bounds {
+    /// Largest borsh encoding of the bounds.
+    pub const LEN: usize = 2 * (1 + CounterValue::LEN) + 1;
+
+    /// Checks that both bounds are of `kind` and that `min <= max`.
+    pub fn check(&self, kind: CounterKind) -> Result<(), CounterError> {
+        for bound in [self.min, self.max].into_iter().flatten() {
+            if bound.kind() != kind {
+                return Err(CounterError::KindMismatch);
+            }
+        }
+        if let (Some(min), Some(max)) = (self.min, self.max) {
+            if min.to_i128() > max.to_i128() {
+                return Err(CounterError::InvalidBounds);
+            }
+        }
+        Ok(())
+    }
+
+    /// Returns `value` if it is in range, otherwise clamps or rejects it.
+    pub fn apply(&self, value: CounterValue) -> Result<CounterValue, CounterError> {
+        let nearest = self.nearest(value);
+        if nearest == value || self.policy == BoundsPolicy::Clamp {
+            Ok(nearest)
+        } else {
+            Err(CounterError::OutOfBounds)
+        }
+    }
+
+    /// Returns `value`, or the bound nearest to it if it is out of range, whatever the policy.
+    pub fn nearest(&self, value: CounterValue) -> CounterValue {
+        match (self.min, self.max) {
+            (Some(min), _) if value.to_i128() < min.to_i128() => min,
+            (_, Some(max)) if value.to_i128() > max.to_i128() => max,
+            _ => value,
+        }
+    }
+}
+
+/// Counter state. On chain it is stored as a `CounterData`, so use `unpack`/`pack` rather than
+/// borsh on account data; the borsh encoding is what `Get` returns.
+#[derive(Clone, Debug, PartialEq, Eq, BorshDeserialize, BorshSerialize)]
+pub struct CounterAccount {
+    pub authority: Pubkey,
+    /// Current value; its variant is the kind the counter was initialized with.
+    pub counter: CounterValue,
+    pub overflow_policy: OverflowPolicy,
+    pub underflow_policy: UnderflowPolicy,
+    pub bounds: Bounds,
+    /// Authority proposed by `ProposeAuthority`; it takes over once it signs `AcceptAuthority`.
+    pub pending_authority: Option<Pubkey>,
+}
+
+/// The fields stored with `U32_LAYOUT_VERSION`.
+#[derive(BorshDeserialize)]
+struct U32LayoutAccount {
+    authority: Pubkey,
+    counter: u32,
+    overflow_policy: OverflowPolicy,
+    underflow_policy: UnderflowPolicy,
+}
+
+/// The fields stored with `KIND_LAYOUT_VERSION`.
+#[derive(BorshDeserialize)]
+struct KindLayoutAccount {
+    authority: Pubkey,
+    counter: CounterValue,
+    overflow_policy: OverflowPolicy,
+    underflow_policy: UnderflowPolicy,
+}
+
+impl CounterAccount {
+    pub const LEN: usize = std::mem::size_of::<CounterData>();
+
+    /// Reads a counter from account data, checking the header first.
+    pub fn unpack(data: &[u8]) -> Result<Self, ProgramError> {
+        Ok(CounterData::load(data)?.account())
+    }
+
+    /// Reads a counter written with an earlier layout version, for `Migrate`.
+    pub fn unpack_outdated(data: &[u8]) -> Result<Self, ProgramError> {
+        check_discriminator(data)?;
+        if data[8] == U32_LAYOUT_VERSION {
+            let account = U32LayoutAccount::deserialize(&mut &data[HEADER_LEN..])?;
+            return Ok(Self {
+                authority: account.authority,
+                counter: CounterValue::U32(account.counter),
+                overflow_policy: account.overflow_policy,
+                underflow_policy: account.underflow_policy,
+                bounds: Bounds::default(),
+                pending_authority: None,
+            });
+        }
+        if data[8] == KIND_LAYOUT_VERSION {
+            let account = KindLayoutAccount::deserialize(&mut &data[HEADER_LEN..])?;
+            return Ok(Self {
+                authority: account.authority,
+                counter: account.counter,
+                overflow_policy: account.overflow_policy,
+                underflow_policy: account.underflow_policy,
+                bounds: Bounds::default(),
+                pending_authority: None,
+            });
+        }
+        Err(CounterError::UnsupportedAccountVersion.into())
+    }
+
+    /// Writes the header and the counter into account data.
+    pub fn pack(&self, dst: &mut [u8]) -> Result<(), ProgramError> {
+        if dst.len() < Self::LEN {
+            return Err(ProgramError::AccountDataTooSmall);
+        }
+        let data: &mut CounterData = bytemuck::from_bytes_mut(&mut dst[..Self::LEN]);
+        *data = CounterData::zeroed();
+        data.discriminator = COUNTER_DISCRIMINATOR;
+        data.version = COUNTER_VERSION;
+        data.store(self);
+        Ok(())
+    }
+}
+
+impl CounterState for CounterAccount {
+    fn authority(&self) -> Pubkey {
+        self.authority
+    }
+
+    fn set_authority(&mut self, authority: Pubkey) {
+        self.authority = authority;
+    }
+
+    fn counter(&self) -> CounterValue {
+        self.counter
+    }
+
+    fn set_counter(&mut self, counter: CounterValue) {
+        self.counter = counter;
+    }
+
+    fn overflow_policy(&self) -> OverflowPolicy {
+        self.overflow_policy
+    }
+
+    fn underflow_policy(&self) -> UnderflowPolicy {
+        self.underflow_policy
+    }
+
+    fn bounds(&self) -> Bounds {
+        self.bounds
+    }
+
+    fn store_bounds(&mut self, bounds: Bounds) {
+        self.bounds = bounds;
+    }
+
+    fn pending_authority(&self) -> Option<Pubkey> {
+        self.pending_authority
+    }
+
+    fn set_pending_authority(&mut self, pending_authority: Option<Pubkey>) {
+        self.pending_authority = pending_authority;
+    }
+}
+
+/// The counter operations, written once over field accessors. `CounterAccount` implements it
+/// for off-chain code, and `CounterData` implements it over the account data so an instruction
+/// only reads and writes the fields it uses.
+pub trait CounterState {
+    fn authority(&self) -> Pubkey;
+    fn set_authority(&mut self, authority: Pubkey);
+    fn counter(&self) -> CounterValue;
+    fn set_counter(&mut self, counter: CounterValue);
+    fn overflow_policy(&self) -> OverflowPolicy;
+    fn underflow_policy(&self) -> UnderflowPolicy;
+    fn bounds(&self) -> Bounds;
+    /// Replaces the bounds as they are; `set_bounds` is the checked operation.
+    fn store_bounds(&mut self, bounds: Bounds);
+    fn pending_authority(&self) -> Option<Pubkey>;
+    fn set_pending_authority(&mut self, pending_authority: Option<Pubkey>);
+
+    fn kind(&self) -> CounterKind {
+        self.counter().kind()
+    }
+
+    fn is_initialized(&self) -> bool {
+        self.authority() != Pubkey::default()
+    }
+
+    fn increment(&mut self, amount: CounterValue) -> Result<(), CounterError> {
+        let kind = self.kind();
+        let value = self.counter().to_i128() + check_amount(kind, amount)?;
+        let counter = match CounterValue::from_i128(kind, value) {
+            Some(counter) => counter,
+            None => match self.overflow_policy() {
+                OverflowPolicy::Error => return Err(CounterError::Overflow),
+                OverflowPolicy::Saturate => CounterValue::wrapping_from_i128(kind, kind.max()),
+                OverflowPolicy::Wrap => CounterValue::wrapping_from_i128(kind, value),
+            },
+        };
+        self.set_counter(self.bounds().apply(counter)?);
+        Ok(())
+    }
+
+    fn decrement(
+        &mut self,
+        amount: CounterValue,
+        policy: UnderflowPolicy,
+    ) -> Result<(), CounterError> {
+        let kind = self.kind();
+        let value = self.counter().to_i128() - check_amount(kind, amount)?;
+        let counter = match CounterValue::from_i128(kind, value) {
+            Some(counter) => counter,
+            None => match policy {
+                UnderflowPolicy::Saturate => CounterValue::wrapping_from_i128(kind, kind.min()),
+                UnderflowPolicy::Error => return Err(CounterError::Underflow),
+            },
+        };
+        self.set_counter(self.bounds().apply(counter)?);
+        Ok(())
+    }
+
+    /// Replaces the value, which must be of the counter's kind.
+    fn set(&mut self, value: CounterValue) -> Result<(), CounterError> {
+        if value.kind() != self.kind() {
+            return Err(CounterError::KindMismatch);
+        }
+        self.set_counter(self.bounds().apply(value)?);
+        Ok(())
+    }
+
+    /// Replaces the value with `new` only if it currently equals `expected`.
+    fn compare_and_swap(
+        &mut self,
+        expected: CounterValue,
+        new: CounterValue,
+    ) -> Result<(), CounterError> {
+        if expected.kind() != self.kind() {
+            return Err(CounterError::KindMismatch);
+        }
+        if self.counter() != expected {
+            return Err(CounterError::CompareMismatch);
+        }
+        self.set(new)
+    }
+
+    /// Sets the value back to zero, or to the nearest bound if zero is out of range; unlike the
+    /// other operations this does not fail under `BoundsPolicy::Reject`.
+    fn reset(&mut self) -> Result<(), CounterError> {
+        self.set_counter(self.bounds().nearest(CounterValue::zero(self.kind())));
+        Ok(())
+    }
+
+    /// Replaces the bounds and brings the current value into the new range.
+    fn set_bounds(&mut self, bounds: Bounds) -> Result<(), CounterError> {
+        bounds.check(self.kind())?;
+        self.set_counter(bounds.apply(self.counter())?);
+        self.store_bounds(bounds);
+        Ok(())
+    }
+
+    /// Records `new_authority` as the pending authority, replacing any earlier proposal.
+    fn propose_authority(&mut self, new_authority: Pubkey) {
+        self.set_pending_authority(Some(new_authority));
+    }
+
+    /// Makes the pending authority, which must be `signer`, the authority.
+    fn accept_authority(&mut self, signer: Pubkey) -> Result<(), CounterError> {
+        match self.pending_authority() {
+            None => Err(CounterError::NoPendingAuthority),
+            Some(pending) if pending != signer => Err(CounterError::Unauthorized),
+            Some(pending) => {
+                self.set_authority(pending);
+                self.set_pending_authority(None);
+                Ok(())
+            }
+        }
+    }
+
+    fn cancel_proposal(&mut self) -> Result<(), CounterError> {
+        if self.pending_authority().is_none() {
+            return Err(CounterError::NoPendingAuthority);
+        }
+        self.set_pending_authority(None);
+        Ok(())
+    }
+}
+
+/// Increment and decrement amounts must be of the counter's kind and not negative.
+fn check_amount(kind: CounterKind, amount: CounterValue) -> Result<i128, CounterError> {
+    if amount.kind() != kind {
+        return Err(CounterError::KindMismatch);
+    }
+    let amount = amount.to_i128();
+    if amount < 0 {
+        return Err(CounterError::NegativeAmount);
+    }
+    Ok(amount)
+}
+
+/// Fixed layout of a counter account. It is cast in place from the account data, so an
+/// instruction only reads and writes the fields it uses instead of decoding the whole account.
+///
+/// Every field is a byte array, so the layout has no padding and no alignment requirement.
+#[repr(C)]
+#[derive(Clone, Copy, Pod, Zeroable)]
+pub struct CounterData {
+    discriminator: [u8; 8],
+    version: u8,
+    kind: u8,
+    overflow_policy: u8,
+    underflow_policy: u8,
+    bounds_policy: u8,
+    has_min: u8,
+    has_max: u8,
+    has_pending_authority: u8,
+    authority: Pubkey,
+    /// Little-endian `CounterValue` bits; `min` and `max` are only meaningful when flagged.
+    counter: [u8; 8],
+    min: [u8; 8],
+    max: [u8; 8],
+    pending_authority: Pubkey,
+    /// Little-endian slot the counter was created in, which tells its permissions apart from
+    /// those granted on an earlier counter at the same address.
+    nonce: [u8; 8],
+}
+
+impl CounterData {
+    /// Casts account data to a counter, checking the header and the enum and flag bytes first
+    /// so that the accessors can read them without failing.
+    pub fn load(data: &[u8]) -> Result<&Self, ProgramError> {
+        check_header(data)?;
+        let counter_data: &Self = bytemuck::from_bytes(&data[..CounterAccount::LEN]);
+        counter_data.check()?;
+        Ok(counter_data)
+    }
+
+    pub fn load_mut(data: &mut [u8]) -> Result<&mut Self, ProgramError> {
+        Self::load(data)?;
+        Ok(bytemuck::from_bytes_mut(&mut data[..CounterAccount::LEN]))
+    }
+
+    pub fn account(&self) -> CounterAccount {
+        CounterAccount {
+            authority: self.authority,
+            counter: self.counter(),
+            overflow_policy: self.overflow_policy(),
+            underflow_policy: self.underflow_policy(),
+            bounds: self.bounds(),
+            pending_authority: self.pending_authority(),
+        }
+    }
+
+    pub fn nonce(&self) -> u64 {
+        u64::from_le_bytes(self.nonce)
+    }
+
+    pub fn set_nonce(&mut self, nonce: u64) {
+        self.nonce = nonce.to_le_bytes();
+    }
+
+    /// Writes every field of `counter_account`, leaving the header and the nonce alone.
+    pub fn store(&mut self, counter_account: &CounterAccount) {
+        self.kind = counter_account.kind() as u8;
+        self.authority = counter_account.authority;
+        self.counter = counter_account.counter.to_bits().to_le_bytes();
+        self.overflow_policy = counter_account.overflow_policy as u8;
+        self.underflow_policy = counter_account.underflow_policy as u8;
+        self.store_bounds(counter_account.bounds);
+        self.set_pending_authority(counter_account.pending_authority);
+    }
+
+    fn check(&self) -> Result<(), ProgramError> {
+        decode::<CounterKind>(self.kind)?;
+        decode::<OverflowPolicy>(self.overflow_policy)?;
+        decode::<UnderflowPolicy>(self.underflow_policy)?;
+        decode::<BoundsPolicy>(self.bounds_policy)?;
+        if [self.has_min, self.has_max, self.has_pending_authority]
+            .iter()
+            .any(|flag| *flag > 1)
+        {
+            return Err(ProgramError::InvalidAccountData);
+        }
+        Ok(())
+    }
+
+    fn value(&self, bits: [u8; 8]) -> CounterValue {
+        CounterValue::from_bits(self.kind(), u64::from_le_bytes(bits))
+    }
+}
+
+/// The enum bytes were checked by `CounterData::load`, so the accessors decode them with the
+/// default as an unreachable fallback.
+impl CounterState for CounterData {
+    fn authority(&self) -> Pubkey {
+        self.authority
+    }
+
+    fn set_authority(&mut self, authority: Pubkey) {
+        self.authority = authority;
+    }
+
+    fn kind(&self) -> CounterKind {
+        CounterKind::from_u8(self.kind).unwrap_or_default()
+    }
+
+    fn counter(&self) -> CounterValue {
+        self.value(self.counter)
+    }
+
+    fn set_counter(&mut self, counter: CounterValue) {
+        self.counter = counter.to_bits().to_le_bytes();
+    }
+
+    fn overflow_policy(&self) -> OverflowPolicy {
+        OverflowPolicy::from_u8(self.overflow_policy).unwrap_or_default()
+    }
+
+    fn underflow_policy(&self) -> UnderflowPolicy {
+        UnderflowPolicy::from_u8(self.underflow_policy).unwrap_or_default()
+    }
+
+    fn bounds(&self) -> Bounds {
+        Bounds {
+            min: (self.has_min == 1).then(|| self.value(self.min)),
+            max: (self.has_max == 1).then(|| self.value(self.max)),
+            policy: BoundsPolicy::from_u8(self.bounds_policy).unwrap_or_default(),
+        }
+    }
+
+    fn store_bounds(&mut self, bounds: Bounds) {
+        self.has_min = bounds.min.is_some().into();
+        self.min = bounds.min.map_or(0, CounterValue::to_bits).to_le_bytes();
+        self.has_max = bounds.max.is_some().into();
+        self.max = bounds.max.map_or(0, CounterValue::to_bits).to_le_bytes();
+        self.bounds_policy = bounds.policy as u8;
+    }
+
+    fn pending_authority(&self) -> Option<Pubkey> {
+        (self.has_pending_authority == 1).then_some(self.pending_authority)
+    }
+
+    fn set_pending_authority(&mut self, pending_authority: Option<Pubkey>) {
+        self.has_pending_authority = pending_authority.is_some().into();
+        self.pending_authority = pending_authority.unwrap_or_default();
+    }
+}
+
+fn check_discriminator(data: &[u8]) -> Result<(), ProgramError> {
+    if data.len() == LEGACY_LEN {
+        return Err(CounterError::LegacyAccount.into());
+    }
+    if data.len() >= HEADER_LEN && data[..8] == CLOSED_DISCRIMINATOR {
+        return Err(CounterError::AccountClosed.into());
+    }
+    if data.len() < HEADER_LEN || data[..8] != COUNTER_DISCRIMINATOR {
+        return Err(CounterError::InvalidAccountDiscriminator.into());
+    }
+    Ok(())
+}
+
+fn check_header(data: &[u8]) -> Result<(), ProgramError> {
+    check_discriminator(data)?;
+    if data[8] != COUNTER_VERSION {
+        return Err(CounterError::UnsupportedAccountVersion.into());
+    }
+    if data.len() < CounterAccount::LEN {
+        return Err(ProgramError::AccountDataTooSmall);
+    }
+    Ok(())
+}
+
+fn decode<T: FromPrimitive>(byte: u8) -> Result<T, ProgramError> {
+    T::from_u8(byte).ok_or(ProgramError::InvalidAccountData)
+}
+
+/// An M-of-N authority: a counter whose authority is the address of a `Multisig` account
+/// accepts an instruction once `threshold` of its members have signed it.
+#[repr(C)]
+#[derive(Clone, Copy, Pod, Zeroable)]
+pub struct Multisig {
+    discriminator: [u8; 8],
+    threshold: u8,
+    len: u8,
+    signers: [Pubkey; MAX_SIGNERS],
+}
+
+impl Multisig {
+    pub const LEN: usize = std::mem::size_of::<Self>();
+
+    /// Checks that `signers` are distinct and that `1 <= threshold <= signers.len()`.
+    pub fn new(threshold: u8, signers: &[Pubkey]) -> Result<Self, CounterError> {
+        if threshold == 0 || usize::from(threshold) > signers.len() || signers.len() > MAX_SIGNERS {
+            return Err(CounterError::InvalidMultisig);
+        }
+        let mut multisig = Self::zeroed();
+        for (index, signer) in signers.iter().enumerate() {
+            if signers[..index].contains(signer) {
+                return Err(CounterError::InvalidMultisig);
+            }
+            multisig.signers[index] = *signer;
+        }
+        multisig.discriminator = MULTISIG_DISCRIMINATOR;
+        multisig.threshold = threshold;
+        multisig.len = signers.len() as u8;
+        Ok(multisig)
+    }
+
+    /// Casts account data to a multisig, checking the discriminator first.
+    pub fn load(data: &[u8]) -> Result<&Self, ProgramError> {
+        if data.len() < HEADER_LEN || data[..8] != MULTISIG_DISCRIMINATOR {
+            return Err(CounterError::InvalidAccountDiscriminator.into());
+        }
+        if data.len() != Self::LEN {
+            return Err(CounterError::InvalidAccountDataLength.into());
+        }
+        let multisig: &Self = bytemuck::from_bytes(data);
+        if usize::from(multisig.len) > MAX_SIGNERS {
+            return Err(ProgramError::InvalidAccountData);
+        }
+        Ok(multisig)
+    }
+
+    pub fn pack(&self, dst: &mut [u8]) -> Result<(), ProgramError> {
+        if dst.len() != Self::LEN {
+            return Err(CounterError::InvalidAccountDataLength.into());
+        }
+        dst.copy_from_slice(bytemuck::bytes_of(self));
+        Ok(())
+    }
+
+    pub fn threshold(&self) -> u8 {
+        self.threshold
+    }
+
+    pub fn signers(&self) -> &[Pubkey] {
+        &self.signers[..usize::from(self.len)]
+    }
+}
+
+/// A right another key can be granted on a counter. The counter authority implicitly holds all
+/// of them.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, BorshDeserialize, BorshSerialize)]
+pub enum Role {
+    /// `Increment`.
+    Incrementer,
+    /// `Decrement` and `TryDecrement`.
+    Decrementer,
+    /// `Update` and `CompareAndSwap`.
+    Updater,
+    /// `Reset`.
+    Resetter,
+    /// Every other role, plus `SetBounds` and granting or revoking the other roles.
+    Admin,
+}
+
+/// Set of roles, stored as one bit per `Role`.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct Roles(u8);
+
+impl Roles {
+    pub fn from_bits(bits: u8) -> Self {
+        Self(bits)
+    }
+
+    pub fn bits(self) -> u8 {
+        self.0
+    }
+
+    pub fn contains(self, role: Role) -> bool {
+        self.0 & Self::bit(role) != 0
+    }
+
+    /// Whether the set holds `role`, directly or through `Role::Admin`.
+    pub fn allows(self, role: Role) -> bool {
+        self.contains(role) || self.contains(Role::Admin)
+    }
+
+    pub fn with(self, role: Role) -> Self {
+        Self(self.0 | Self::bit(role))
+    }
+
+    pub fn without(self, role: Role) -> Self {
+        Self(self.0 & !Self::bit(role))
+    }
+
+    fn bit(role: Role) -> u8 {
+        1 << role as u8
+    }
+}
+
+impl FromIterator<Role> for Roles {
+    fn from_iter<I: IntoIterator<Item = Role>>(roles: I) -> Self {
+        roles.into_iter().fold(Self::default(), Self::with)
+    }
+}
+
+/// The roles of one member on one counter, stored in the PDA derived by
+/// `find_permissions_address`. The PDA outlives the counter when it is closed without it, so
+/// the roles only hold for the counter whose nonce they were granted under.
+#[repr(C)]
+#[derive(Clone, Copy, Pod, Zeroable)]
+pub struct Permissions {
+    discriminator: [u8; 8],
+    counter: Pubkey,
+    member: Pubkey,
+    nonce: [u8; 8],
+    roles: u8,
+}
+
+impl Permissions {
+    pub const LEN: usize = std::mem::size_of::<Self>();
+
+    pub fn new(counter: Pubkey, member: Pubkey, nonce: u64) -> Self {
+        Self {
+            discriminator: PERMISSIONS_DISCRIMINATOR,
+            counter,
+            member,
+            nonce: nonce.to_le_bytes(),
+            roles: 0,
+        }
+    }
+
+    /// Casts account data to permissions, checking the discriminator first.
+    pub fn load(data: &[u8]) -> Result<&Self, ProgramError> {
+        Self::check(data)?;
+        Ok(bytemuck::from_bytes(data))
+    }
+
+    pub fn load_mut(data: &mut [u8]) -> Result<&mut Self, ProgramError> {
+        Self::check(data)?;
+        Ok(bytemuck::from_bytes_mut(data))
+    }
+
+    pub fn pack(&self, dst: &mut [u8]) -> Result<(), ProgramError> {
+        if dst.len() != Self::LEN {
+            return Err(CounterError::InvalidAccountDataLength.into());
+        }
+        dst.copy_from_slice(bytemuck::bytes_of(self));
+        Ok(())
+    }
+
+    pub fn counter(&self) -> &Pubkey {
+        &self.counter
+    }
+
+    pub fn member(&self) -> &Pubkey {
+        &self.member
+    }
+
+    pub fn nonce(&self) -> u64 {
+        u64::from_le_bytes(self.nonce)
+    }
+
+    pub fn roles(&self) -> Roles {
+        Roles::from_bits(self.roles)
+    }
+
+    /// The roles granted on the counter with `nonce`; none if they were granted on an earlier
+    /// counter at the same address.
+    pub fn roles_for(&self, nonce: u64) -> Roles {
+        if self.nonce() == nonce {
+            self.roles()
+        } else {
+            Roles::default()
+        }
+    }
+
+    /// Replaces the roles, granting them on the counter with `nonce`.
+    pub fn set_roles(&mut self, nonce: u64, roles: Roles) {
+        self.nonce = nonce.to_le_bytes();
+        self.roles = roles.bits();
+    }
+
+    fn check(data: &[u8]) -> Result<(), ProgramError> {
+        if data.len() < HEADER_LEN || data[..8] != PERMISSIONS_DISCRIMINATOR {
+            return Err(CounterError::InvalidAccountDiscriminator.into());
+        }
+        if data.len() != Self::LEN {
+            return Err(CounterError::InvalidAccountDataLength.into());
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_decrement_underflow_policy() {
+        let mut counter_account = CounterAccount {
+            authority: Pubkey::new_unique(),
+            counter: CounterValue::U32(10),
+            overflow_policy: OverflowPolicy::Error,
+            underflow_policy: UnderflowPolicy::Error,
+            bounds: Bounds::default(),
+            pending_authority: None,
+        };
+        assert_eq!(
+            counter_account.decrement(CounterValue::U32(11), UnderflowPolicy::Error),
+            Err(CounterError::Underflow)
+        );
+        assert_eq!(counter_account.counter, CounterValue::U32(10));
+        counter_account
+            .decrement(CounterValue::U32(11), UnderflowPolicy::Saturate)
+            .unwrap();
+        assert_eq!(counter_account.counter, CounterValue::U32(0));
+
+        counter_account.counter = CounterValue::I64(3);
+        counter_account
+            .decrement(CounterValue::I64(5), UnderflowPolicy::Error)
+            .unwrap();
+        assert_eq!(counter_account.counter, CounterValue::I64(-2));
+    }
+
+    #[test]
+    fn test_counter_account_header() {
+        let counter_account = CounterAccount {
+            authority: Pubkey::new_unique(),
+            counter: CounterValue::U32(9),
+            overflow_policy: OverflowPolicy::Wrap,
+            underflow_policy: UnderflowPolicy::Error,
+            bounds: Bounds::default(),
+            pending_authority: None,
+        };
+        let mut data = vec![0; CounterAccount::LEN];
+        counter_account.pack(&mut data).unwrap();
+        assert_eq!(data[..8], COUNTER_DISCRIMINATOR);
+        assert_eq!(data[8], COUNTER_VERSION);
+        assert_eq!(
+            CounterAccount::unpack(&data).unwrap().counter,
+            CounterValue::U32(9)
+        );
+
+        data[8] = COUNTER_VERSION + 1;
+        assert_eq!(
+            CounterAccount::unpack(&data).err(),
+            Some(CounterError::UnsupportedAccountVersion.into())
+        );
+        data[0] ^= 0xff;
+        assert_eq!(
+            CounterAccount::unpack(&data).err(),
+            Some(CounterError::InvalidAccountDiscriminator.into())
+        );
+        assert_eq!(
+            CounterAccount::unpack(&9u32.to_le_bytes()).err(),
+            Some(CounterError::LegacyAccount.into())
+        );
+    }
+
+    #[test]
+    fn test_counter_data_layout() {
+        assert_eq!(CounterAccount::LEN, 112);
+        let counter_account = CounterAccount {
+            authority: Pubkey::new_unique(),
+            counter: CounterValue::I64(-3),
+            overflow_policy: OverflowPolicy::Saturate,
+            underflow_policy: UnderflowPolicy::Error,
+            bounds: Bounds {
+                min: Some(CounterValue::I64(-10)),
+                max: None,
+                policy: BoundsPolicy::Clamp,
+            },
+            pending_authority: None,
+        };
+        let mut data = vec![0; CounterAccount::LEN];
+        counter_account.pack(&mut data).unwrap();
+        assert_eq!(data[48..56], (-3i64).to_le_bytes());
+        assert_eq!(CounterAccount::unpack(&data), Ok(counter_account.clone()));
+
+        let mut next = counter_account.clone();
+        next.increment(CounterValue::I64(7)).unwrap();
+        let before = data.clone();
+        CounterData::load_mut(&mut data)
+            .unwrap()
+            .increment(CounterValue::I64(7))
+            .unwrap();
+        let changed: Vec<usize> = (0..data.len()).filter(|&i| data[i] != before[i]).collect();
+        assert!(changed.iter().all(|i| (48..56).contains(i)));
+        assert_eq!(CounterAccount::unpack(&data), Ok(next));
+
+        data[9] = 7;
+        assert_eq!(
+            CounterAccount::unpack(&data),
+            Err(ProgramError::InvalidAccountData)
+        );
+        assert_eq!(
+            CounterAccount::unpack(&data[..40]),
+            Err(ProgramError::AccountDataTooSmall)
+        );
+        data[9] = CounterKind::I64 as u8;
+        data[15] = 2;
+        assert_eq!(
+            CounterAccount::unpack(&data),
+            Err(ProgramError::InvalidAccountData)
+        );
+
+        data[15] = 0;
+        assert_eq!(
+            CounterAccount::unpack_outdated(&data),
+            Err(CounterError::UnsupportedAccountVersion.into())
+        );
+    }
+
+    #[test]
+    fn test_unpack_outdated_layouts() {
+        let authority = Pubkey::new_unique();
+        let mut data = COUNTER_DISCRIMINATOR.to_vec();
+        data.push(U32_LAYOUT_VERSION);
+        data.extend_from_slice(authority.as_ref());
+        data.extend_from_slice(&9u32.to_le_bytes());
+        data.extend_from_slice(&[OverflowPolicy::Wrap as u8, UnderflowPolicy::Error as u8]);
+        assert_eq!(
+            CounterAccount::unpack_outdated(&data),
+            Ok(CounterAccount {
+                authority,
+                counter: CounterValue::U32(9),
+                overflow_policy: OverflowPolicy::Wrap,
+                underflow_policy: UnderflowPolicy::Error,
+                bounds: Bounds::default(),
+                pending_authority: None,
+            })
+        );
+
+        let mut data = COUNTER_DISCRIMINATOR.to_vec();
+        data.push(KIND_LAYOUT_VERSION);
+        data.extend_from_slice(authority.as_ref());
+        data.extend_from_slice(&borsh::to_vec(&CounterValue::I64(-4)).unwrap());
+        data.extend_from_slice(&[OverflowPolicy::Error as u8, UnderflowPolicy::Saturate as u8]);
+        assert_eq!(
+            CounterAccount::unpack_outdated(&data),
+            Ok(CounterAccount {
+                authority,
+                counter: CounterValue::I64(-4),
+                overflow_policy: OverflowPolicy::Error,
+                underflow_policy: UnderflowPolicy::Saturate,
+                bounds: Bounds::default(),
+                pending_authority: None,
+            })
+        );
+    }
+
+    #[test]
+    fn test_multisig_config() {
+        let signers = [Pubkey::new_unique(), Pubkey::new_unique()];
+        let multisig = Multisig::new(2, &signers).unwrap();
+        assert_eq!(multisig.signers(), signers);
+
+        let mut data = vec![0; Multisig::LEN];
+        multisig.pack(&mut data).unwrap();
+        assert_eq!(Multisig::load(&data).unwrap().threshold(), 2);
+
+        for (threshold, signers) in [
+            (0, &signers[..]),
+            (3, &signers[..]),
+            (1, &[signers[0], signers[0]][..]),
+            (1, &[signers[0]; MAX_SIGNERS + 1][..]),
+        ] {
+            assert_eq!(
+                Multisig::new(threshold, signers).err(),
+                Some(CounterError::InvalidMultisig)
+            );
+        }
+    }
+
+    #[test]
+    fn test_counter_bounds() {
+        use CounterValue::I64;
+
+        let mut counter_account = CounterAccount {
+            authority: Pubkey::new_unique(),
+            counter: I64(0),
+            overflow_policy: OverflowPolicy::Error,
+            underflow_policy: UnderflowPolicy::Saturate,
+            bounds: Bounds::default(),
+            pending_authority: None,
+        };
+        assert_eq!(
+            counter_account.set_bounds(Bounds {
+                min: Some(I64(5)),
+                max: Some(I64(-5)),
+                policy: BoundsPolicy::Reject,
+            }),
+            Err(CounterError::InvalidBounds)
+        );
+        assert_eq!(
+            counter_account.set_bounds(Bounds {
+                min: Some(CounterValue::U64(0)),
+                max: None,
+                policy: BoundsPolicy::Reject,
+            }),
+            Err(CounterError::KindMismatch)
+        );
+
+        counter_account
+            .set_bounds(Bounds {
+                min: Some(I64(-10)),
+                max: Some(I64(10)),
+                policy: BoundsPolicy::Reject,
+            })
+            .unwrap();
+        assert_eq!(
+            counter_account.increment(I64(11)),
+            Err(CounterError::OutOfBounds)
+        );
+        assert_eq!(
+            counter_account.decrement(I64(11), UnderflowPolicy::Saturate),
+            Err(CounterError::OutOfBounds)
+        );
+        assert_eq!(
+            counter_account.set(I64(-11)),
+            Err(CounterError::OutOfBounds)
+        );
+        assert_eq!(counter_account.counter, I64(0));
+
+        counter_account.bounds.policy = BoundsPolicy::Clamp;
+        counter_account.increment(I64(11)).unwrap();
+        assert_eq!(counter_account.counter, I64(10));
+        counter_account
+            .decrement(I64(100), UnderflowPolicy::Error)
+            .unwrap();
+        assert_eq!(counter_account.counter, I64(-10));
+        counter_account.set(I64(42)).unwrap();
+        assert_eq!(counter_account.counter, I64(10));
+
+        counter_account
+            .set_bounds(Bounds {
+                min: Some(I64(1)),
+                max: None,
+                policy: BoundsPolicy::Clamp,
+            })
+            .unwrap();
+        counter_account.reset().unwrap();
+        assert_eq!(counter_account.counter, I64(1));
+
+        counter_account.set(I64(7)).unwrap();
+        counter_account
+            .set_bounds(Bounds {
+                min: Some(I64(5)),
+                max: None,
+                policy: BoundsPolicy::Reject,
+            })
+            .unwrap();
+        counter_account.reset().unwrap();
+        assert_eq!(counter_account.counter, I64(5));
+    }
+}
@@ -0,0 +1,491 @@
+//! The counter rules as a pure function of the counter, the instruction and who sends it. The
+//! on-chain processor runs them through `apply_in_place` on the account data and only adds
+//! account validation around it, so off-chain simulators calling `apply` get exactly the same
+//! results.
+
+use crate::error::CounterError;
+use crate::events::{CounterEvent, Operation};
+use crate::instructions::{CounterInstructions, Op};
+use crate::state::{CounterAccount, CounterState, CounterValue, Role, Roles, UnderflowPolicy};
+use solana_program::pubkey::Pubkey;
+
+/// Where and by whom an instruction is applied.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Context {
+    /// Address of the counter account.
+    pub counter: Pubkey,
+    /// Signer sending the instruction, or the multisig whose members signed it; checked against
+    /// the counter authority.
+    pub signer: Pubkey,
+    pub slot: u64,
+    /// Roles the signer was granted on the counter; only consulted when it is not the authority.
+    pub roles: Roles,
+}
+
+impl Context {
+    pub fn event(
+        &self,
+        operation: Operation,
+        old: Option<CounterValue>,
+        new: Option<CounterValue>,
+    ) -> CounterEvent {
+        CounterEvent {
+            counter: self.counter,
+            operation,
+            old,
+            new,
+            actor: self.signer,
+            slot: self.slot,
+            member: None,
+            role: None,
+        }
+    }
+}
+
+/// Why `apply_in_place` rejected an instruction.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Rejection {
+    pub error: CounterError,
+    /// Index of the failing op when the instruction is a `Batch`.
+    pub batch_op: Option<usize>,
+}
+
+impl From<CounterError> for Rejection {
+    fn from(error: CounterError) -> Self {
+        Rejection {
+            error,
+            batch_op: None,
+        }
+    }
+}
+
+/// Returns the counter as it is after `instruction`, leaving `counter_account` untouched.
+///
+/// `FanOut` applies its op to this one counter. `GrantRole` and `RevokeRole` only check that
+/// the signer may change the member's roles; the roles live in a separate account.
+/// `Initialize`, `Migrate`, `Close`, `InitializeMultisig` and `ClosePermissions` create or
+/// delete accounts rather than change the counter, and fail with `CounterError::NotApplicable`.
+pub fn apply(
+    counter_account: &CounterAccount,
+    instruction: &CounterInstructions,
+    context: &Context,
+) -> Result<CounterAccount, CounterError> {
+    apply_with_events(counter_account, instruction, context).map(|(next, _)| next)
+}
+
+/// Like `apply`, also returning the events the program logs for the instruction.
+pub fn apply_with_events(
+    counter_account: &CounterAccount,
+    instruction: &CounterInstructions,
+    context: &Context,
+) -> Result<(CounterAccount, Vec<CounterEvent>), CounterError> {
+    let mut next = counter_account.clone();
+    let events =
+        apply_in_place(&mut next, instruction, context).map_err(|rejection| rejection.error)?;
+    Ok((next, events))
+}
+
+/// Like `apply_with_events`, but changes `counter` itself, which on chain is the account data.
+/// Nothing is changed when the instruction fails.
+pub fn apply_in_place<C: CounterState + Clone>(
+    counter: &mut C,
+    instruction: &CounterInstructions,
+    context: &Context,
+) -> Result<Vec<CounterEvent>, Rejection> {
+    let mut events = vec![];
+    if *instruction == CounterInstructions::Get {
+        return Ok(events);
+    }
+    // The proposed authority signs `AcceptAuthority`; everything else needs the current one or
+    // a member holding the matching role.
+    if *instruction != CounterInstructions::AcceptAuthority
+        && counter.authority() != context.signer
+        && !is_allowed(instruction, context.roles)
+    {
+        return Err(CounterError::Unauthorized.into());
+    }
+
+    match instruction {
+        CounterInstructions::Increment(args) => {
+            events.push(apply_op(counter, &Op::Increment(args.value), context)?);
+        }
+        CounterInstructions::Decrement(args) => {
+            events.push(apply_op(counter, &Op::Decrement(args.value), context)?);
+        }
+        CounterInstructions::TryDecrement(args) => {
+            events.push(apply_op(counter, &Op::TryDecrement(args.value), context)?);
+        }
+        CounterInstructions::Reset => {
+            events.push(apply_op(counter, &Op::Reset, context)?);
+        }
+        CounterInstructions::Update(args) => {
+            events.push(apply_op(counter, &Op::Update(args.value), context)?);
+        }
+        CounterInstructions::CompareAndSwap(args) => {
+            events.push(apply_op(
+                counter,
+                &Op::CompareAndSwap(args.clone()),
+                context,
+            )?);
+        }
+        CounterInstructions::FanOut(args) => {
+            events.push(apply_op(counter, &args.op, context)?);
+        }
+        CounterInstructions::Batch(ops) => {
+            // Ops run on a copy that is only written back once all of them succeed.
+            let mut next = counter.clone();
+            for (index, op) in ops.iter().enumerate() {
+                let event = apply_op(&mut next, op, context).map_err(|error| Rejection {
+                    error,
+                    batch_op: Some(index),
+                })?;
+                events.push(event);
+            }
+            *counter = next;
+        }
+        CounterInstructions::SetBounds(bounds) => {
+            let old = counter.counter();
+            counter.set_bounds(*bounds)?;
+            events.push(context.event(Operation::SetBounds, Some(old), Some(counter.counter())));
+        }
+        CounterInstructions::ProposeAuthority(new_authority) => {
+            counter.propose_authority(*new_authority);
+            events.push(context.event(
+                Operation::ProposeAuthority,
+                Some(counter.counter()),
+                Some(counter.counter()),
+            ));
+        }
+        CounterInstructions::AcceptAuthority => {
+            counter.accept_authority(context.signer)?;
+            events.push(context.event(
+                Operation::AcceptAuthority,
+                Some(counter.counter()),
+                Some(counter.counter()),
+            ));
+        }
+        CounterInstructions::CancelProposal => {
+            counter.cancel_proposal()?;
+            events.push(context.event(
+                Operation::CancelProposal,
+                Some(counter.counter()),
+                Some(counter.counter()),
+            ));
+        }
+        CounterInstructions::GrantRole(args) => {
+            events.push(CounterEvent {
+                member: Some(args.member),
+                role: Some(args.role),
+                ..context.event(
+                    Operation::GrantRole,
+                    Some(counter.counter()),
+                    Some(counter.counter()),
+                )
+            });
+        }
+        CounterInstructions::RevokeRole(args) => {
+            events.push(CounterEvent {
+                member: Some(args.member),
+                role: Some(args.role),
+                ..context.event(
+                    Operation::RevokeRole,
+                    Some(counter.counter()),
+                    Some(counter.counter()),
+                )
+            });
+        }
+        CounterInstructions::Initialize(_)
+        | CounterInstructions::Migrate
+        | CounterInstructions::Close
+        | CounterInstructions::InitializeMultisig(_)
+        | CounterInstructions::ClosePermissions => return Err(CounterError::NotApplicable.into()),
+        CounterInstructions::Get => unreachable!(),
+    }
+    Ok(events)
+}
+
+/// Whether a member holding `roles` may send `instruction`. Changing the authority and closing
+/// the counter are left to the authority alone, and only the authority can make admins.
+fn is_allowed(instruction: &CounterInstructions, roles: Roles) -> bool {
+    match instruction {
+        CounterInstructions::Increment(_) => roles.allows(Role::Incrementer),
+        CounterInstructions::Decrement(_) | CounterInstructions::TryDecrement(_) => {
+            roles.allows(Role::Decrementer)
+        }
+        CounterInstructions::Update(_) | CounterInstructions::CompareAndSwap(_) => {
+            roles.allows(Role::Updater)
+        }
+        CounterInstructions::Reset => roles.allows(Role::Resetter),
+        CounterInstructions::Batch(ops) => ops.iter().all(|op| roles.allows(op.role())),
+        CounterInstructions::FanOut(args) => roles.allows(args.op.role()),
+        CounterInstructions::SetBounds(_) => roles.contains(Role::Admin),
+        CounterInstructions::GrantRole(args) | CounterInstructions::RevokeRole(args) => {
+            args.role != Role::Admin && roles.contains(Role::Admin)
+        }
+        _ => false,
+    }
+}
+
+/// Applies a single op and returns the matching event.
+fn apply_op(
+    counter: &mut impl CounterState,
+    op: &Op,
+    context: &Context,
+) -> Result<CounterEvent, CounterError> {
+    let old = counter.counter();
+    match op {
+        Op::Increment(amount) => counter.increment(*amount),
+        Op::Decrement(amount) => {
+            let policy = counter.underflow_policy();
+            counter.decrement(*amount, policy)
+        }
+        Op::TryDecrement(amount) => counter.decrement(*amount, UnderflowPolicy::Error),
+        Op::Update(value) => counter.set(*value),
+        Op::Reset => counter.reset(),
+        Op::CompareAndSwap(args) => counter.compare_and_swap(args.expected, args.new),
+    }?;
+    Ok(context.event(op.operation(), Some(old), Some(counter.counter())))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::instructions::{RoleArgs, UpdateArgs};
+    use crate::state::{Bounds, OverflowPolicy};
+
+    #[test]
+    fn test_apply() {
+        let context = Context {
+            counter: Pubkey::new_unique(),
+            signer: Pubkey::new_unique(),
+            slot: 7,
+            roles: Roles::default(),
+        };
+        let counter_account = CounterAccount {
+            authority: context.signer,
+            counter: CounterValue::U64(5),
+            overflow_policy: OverflowPolicy::Error,
+            underflow_policy: UnderflowPolicy::Saturate,
+            bounds: Bounds::default(),
+            pending_authority: None,
+        };
+        let increment = CounterInstructions::Increment(UpdateArgs {
+            value: CounterValue::U64(2),
+        });
+
+        let next = apply(&counter_account, &increment, &context).unwrap();
+        assert_eq!(next.counter, CounterValue::U64(7));
+        assert_eq!(counter_account.counter, CounterValue::U64(5));
+
+        let batch = CounterInstructions::Batch(vec![
+            Op::Decrement(CounterValue::U64(9)),
+            Op::Increment(CounterValue::U64(1)),
+        ]);
+        let (next, events) = apply_with_events(&counter_account, &batch, &context).unwrap();
+        assert_eq!(next.counter, CounterValue::U64(1));
+        assert_eq!(
+            events,
+            vec![
+                context.event(
+                    Operation::Decrement,
+                    Some(CounterValue::U64(5)),
+                    Some(CounterValue::U64(0))
+                ),
+                context.event(
+                    Operation::Increment,
+                    Some(CounterValue::U64(0)),
+                    Some(CounterValue::U64(1))
+                ),
+            ]
+        );
+
+        let failing = CounterInstructions::Batch(vec![
+            Op::Increment(CounterValue::U64(1)),
+            Op::TryDecrement(CounterValue::U64(9)),
+        ]);
+        assert_eq!(
+            apply(&counter_account, &failing, &context),
+            Err(CounterError::Underflow)
+        );
+        let mut unchanged = counter_account.clone();
+        assert_eq!(
+            apply_in_place(&mut unchanged, &failing, &context),
+            Err(Rejection {
+                error: CounterError::Underflow,
+                batch_op: Some(1),
+            })
+        );
+        assert_eq!(unchanged, counter_account);
+
+        let stranger = Context {
+            signer: Pubkey::new_unique(),
+            ..context
+        };
+        assert_eq!(
+            apply(&counter_account, &increment, &stranger),
+            Err(CounterError::Unauthorized)
+        );
+        assert_eq!(
+            apply(&counter_account, &CounterInstructions::Get, &stranger),
+            Ok(counter_account.clone())
+        );
+        assert_eq!(
+            apply(&counter_account, &CounterInstructions::Close, &context),
+            Err(CounterError::NotApplicable)
+        );
+    }
+
+    #[test]
+    fn test_authority_transfer() {
+        let context = Context {
+            counter: Pubkey::new_unique(),
+            signer: Pubkey::new_unique(),
+            slot: 0,
+            roles: Roles::default(),
+        };
+        let successor = Context {
+            signer: Pubkey::new_unique(),
+            ..context
+        };
+        let counter_account = CounterAccount {
+            authority: context.signer,
+            counter: CounterValue::U32(1),
+            overflow_policy: OverflowPolicy::Error,
+            underflow_policy: UnderflowPolicy::Saturate,
+            bounds: Bounds::default(),
+            pending_authority: None,
+        };
+        let accept = CounterInstructions::AcceptAuthority;
+        let reset = CounterInstructions::Reset;
+
+        assert_eq!(
+            apply(&counter_account, &accept, &successor),
+            Err(CounterError::NoPendingAuthority)
+        );
+        let propose = CounterInstructions::ProposeAuthority(successor.signer);
+        assert_eq!(
+            apply(&counter_account, &propose, &successor),
+            Err(CounterError::Unauthorized)
+        );
+        let proposed = apply(&counter_account, &propose, &context).unwrap();
+        assert_eq!(proposed.pending_authority, Some(successor.signer));
+
+        // The old authority keeps control until the proposal is accepted.
+        assert!(apply(&proposed, &reset, &context).is_ok());
+        assert_eq!(
+            apply(&proposed, &reset, &successor),
+            Err(CounterError::Unauthorized)
+        );
+        assert_eq!(
+            apply(&proposed, &accept, &context),
+            Err(CounterError::Unauthorized)
+        );
+
+        let cancelled = apply(&proposed, &CounterInstructions::CancelProposal, &context).unwrap();
+        assert_eq!(cancelled, counter_account);
+        assert_eq!(
+            apply(&cancelled, &CounterInstructions::CancelProposal, &context),
+            Err(CounterError::NoPendingAuthority)
+        );
+
+        let accepted = apply(&proposed, &accept, &successor).unwrap();
+        assert_eq!(accepted.authority, successor.signer);
+        assert_eq!(accepted.pending_authority, None);
+        assert!(apply(&accepted, &reset, &successor).is_ok());
+        assert_eq!(
+            apply(&accepted, &reset, &context),
+            Err(CounterError::Unauthorized)
+        );
+    }
+
+    #[test]
+    fn test_roles() {
+        let authority = Pubkey::new_unique();
+        let counter_account = CounterAccount {
+            authority,
+            counter: CounterValue::U64(5),
+            overflow_policy: OverflowPolicy::Error,
+            underflow_policy: UnderflowPolicy::Saturate,
+            bounds: Bounds::default(),
+            pending_authority: None,
+        };
+        let member = |roles: &[Role]| Context {
+            counter: Pubkey::new_unique(),
+            signer: Pubkey::new_unique(),
+            slot: 0,
+            roles: roles.iter().copied().collect(),
+        };
+        let one = CounterValue::U64(1);
+        let increment = CounterInstructions::Increment(UpdateArgs { value: one });
+        let reset = CounterInstructions::Reset;
+        let grant = |role| {
+            CounterInstructions::GrantRole(RoleArgs {
+                member: Pubkey::new_unique(),
+                role,
+            })
+        };
+
+        let incrementer = member(&[Role::Incrementer]);
+        assert_eq!(
+            apply(&counter_account, &increment, &incrementer).map(|next| next.counter),
+            Ok(CounterValue::U64(6))
+        );
+        assert_eq!(
+            apply(&counter_account, &reset, &incrementer),
+            Err(CounterError::Unauthorized)
+        );
+        // A batch needs the role of every op in it.
+        let batch = CounterInstructions::Batch(vec![Op::Increment(one), Op::Reset]);
+        assert_eq!(
+            apply(&counter_account, &batch, &incrementer),
+            Err(CounterError::Unauthorized)
+        );
+        assert!(apply(
+            &counter_account,
+            &batch,
+            &member(&[Role::Incrementer, Role::Resetter])
+        )
+        .is_ok());
+        assert_eq!(
+            apply(&counter_account, &grant(Role::Incrementer), &incrementer),
+            Err(CounterError::Unauthorized)
+        );
+
+        let admin = member(&[Role::Admin]);
+        assert!(apply(&counter_account, &reset, &admin).is_ok());
+        assert!(apply(&counter_account, &grant(Role::Resetter), &admin).is_ok());
+        let resetter = Pubkey::new_unique();
+        let grant_resetter = CounterInstructions::GrantRole(RoleArgs {
+            member: resetter,
+            role: Role::Resetter,
+        });
+        let (_, events) = apply_with_events(&counter_account, &grant_resetter, &admin).unwrap();
+        assert_eq!(events[0].operation, Operation::GrantRole);
+        assert_eq!(events[0].member, Some(resetter));
+        assert_eq!(events[0].role, Some(Role::Resetter));
+        assert!(apply(
+            &counter_account,
+            &CounterInstructions::SetBounds(Bounds::default()),
+            &admin
+        )
+        .is_ok());
+        assert_eq!(
+            apply(&counter_account, &grant(Role::Admin), &admin),
+            Err(CounterError::Unauthorized)
+        );
+        assert_eq!(
+            apply(
+                &counter_account,
+                &CounterInstructions::CancelProposal,
+                &admin
+            ),
+            Err(CounterError::Unauthorized)
+        );
+
+        let owner = Context {
+            signer: authority,
+            ..member(&[])
+        };
+        assert!(apply(&counter_account, &grant(Role::Admin), &owner).is_ok());
+    }
+}
@@ -0,0 +1,139 @@
+//! Prints the compute units each instruction consumes. It needs the SBF build of the program,
+//! since builtin processors are not metered:
+//!
+//!     cargo test-sbf --test compute_units -- --ignored --nocapture
+
+use learn_rust_solana_counter::{
+    builder, find_counter_address,
+    instructions::{InitializeArgs, Op},
+    Bounds, CounterKind, CounterValue, OverflowPolicy, Role, UnderflowPolicy,
+};
+use solana_program_test::{tokio, ProgramTest};
+use solana_sdk::{
+    instruction::Instruction, pubkey::Pubkey, signature::Signer, transaction::Transaction,
+};
+
+#[tokio::test]
+#[ignore]
+async fn test_compute_units() {
+    let program_id = Pubkey::new_unique();
+    let mut program_test = ProgramTest::new("learn_rust_solana_counter", program_id, None);
+    program_test.prefer_bpf(true);
+    let (mut banks_client, payer, _) = program_test.start().await;
+
+    let seed = b"compute-units";
+    let (counter, _) = find_counter_address(&program_id, &payer.pubkey(), seed);
+    let authority = payer.pubkey();
+    let value = CounterValue::U64(3);
+    let member = Pubkey::new_unique();
+    let instructions: Vec<(&str, Instruction)> = vec![
+        (
+            "Initialize",
+            builder::initialize(
+                &program_id,
+                &authority,
+                InitializeArgs {
+                    seed: seed.to_vec(),
+                    kind: CounterKind::U64,
+                    overflow_policy: OverflowPolicy::Error,
+                    underflow_policy: UnderflowPolicy::Saturate,
+                    bounds: Bounds::default(),
+                },
+            ),
+        ),
+        (
+            "Increment",
+            builder::increment(&program_id, &counter, &authority, value),
+        ),
+        (
+            "Decrement",
+            builder::decrement(&program_id, &counter, &authority, value),
+        ),
+        (
+            "Update",
+            builder::update(&program_id, &counter, &authority, value),
+        ),
+        (
+            "TryDecrement",
+            builder::try_decrement(&program_id, &counter, &authority, value),
+        ),
+        ("Reset", builder::reset(&program_id, &counter, &authority)),
+        (
+            "SetBounds",
+            builder::set_bounds(&program_id, &counter, &authority, Bounds::default()),
+        ),
+        (
+            "CompareAndSwap",
+            builder::compare_and_swap(
+                &program_id,
+                &counter,
+                &authority,
+                CounterValue::U64(0),
+                value,
+            ),
+        ),
+        (
+            "Batch",
+            builder::batch(
+                &program_id,
+                &counter,
+                &authority,
+                vec![Op::Increment(value), Op::Decrement(value)],
+            ),
+        ),
+        (
+            "FanOut",
+            builder::fan_out(&program_id, &authority, &[counter], Op::Reset),
+        ),
+        ("Get", builder::get(&program_id, &counter)),
+        (
+            "ProposeAuthority",
+            builder::propose_authority(&program_id, &counter, &authority, &Pubkey::new_unique()),
+        ),
+        (
+            "CancelProposal",
+            builder::cancel_proposal(&program_id, &counter, &authority),
+        ),
+        (
+            "GrantRole",
+            builder::grant_role(
+                &program_id,
+                &counter,
+                &authority,
+                &authority,
+                &member,
+                Role::Incrementer,
+            ),
+        ),
+        (
+            "RevokeRole",
+            builder::revoke_role(
+                &program_id,
+                &counter,
+                &authority,
+                &member,
+                Role::Incrementer,
+            ),
+        ),
+        (
+            "Close",
+            builder::close(&program_id, &counter, &authority, &authority),
+        ),
+    ];
+
+    for (name, instruction) in instructions {
+        let recent_blockhash = banks_client.get_latest_blockhash().await.unwrap();
+        let mut transaction = Transaction::new_with_payer(&[instruction], Some(&payer.pubkey()));
+        transaction.sign(&[&payer], recent_blockhash);
+        let result = banks_client
+            .process_transaction_with_metadata(transaction)
+            .await
+            .unwrap();
+        result.result.unwrap();
+        println!(
+            "{:<16} {:>6} CU",
+            name,
+            result.metadata.unwrap().compute_units_consumed
+        );
+    }
+}
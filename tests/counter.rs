@@ -0,0 +1,825 @@
+use learn_rust_solana_counter::state::{COUNTER_DISCRIMINATOR, KIND_LAYOUT_VERSION};
+use learn_rust_solana_counter::{
+    builder,
+    error::CounterError,
+    find_counter_address, find_permissions_address,
+    instructions::{InitializeArgs, Op},
+    process_instruction, Bounds, BoundsPolicy, CounterAccount, CounterKind, CounterValue, Multisig,
+    OverflowPolicy, Permissions, Role, UnderflowPolicy,
+};
+use solana_program_test::{processor, tokio, ProgramTest};
+use solana_sdk::{
+    account::Account,
+    bpf_loader_upgradeable,
+    instruction::{Instruction, InstructionError},
+    pubkey::Pubkey,
+    rent::Rent,
+    signature::{Keypair, Signer},
+    system_instruction,
+    transaction::{Transaction, TransactionError},
+};
+
+fn program_test(program_id: Pubkey) -> ProgramTest {
+    ProgramTest::new(
+        "learn_rust_solana_counter",
+        program_id,
+        processor!(process_instruction),
+    )
+}
+
+#[tokio::test]
+async fn test_initialize_creates_counter_pda() {
+    let program_id = Pubkey::new_unique();
+    let (mut banks_client, payer, recent_blockhash) = program_test(program_id).start().await;
+
+    let seed = b"events";
+    let (counter, _) = find_counter_address(&program_id, &payer.pubkey(), seed);
+    let initialize = builder::initialize(
+        &program_id,
+        &payer.pubkey(),
+        InitializeArgs {
+            seed: seed.to_vec(),
+            kind: CounterKind::I64,
+            overflow_policy: OverflowPolicy::Saturate,
+            underflow_policy: UnderflowPolicy::Error,
+            bounds: Bounds {
+                min: Some(CounterValue::I64(3)),
+                max: None,
+                policy: BoundsPolicy::Clamp,
+            },
+        },
+    );
+    let increment =
+        builder::increment(&program_id, &counter, &payer.pubkey(), CounterValue::I64(7));
+
+    let mut transaction =
+        Transaction::new_with_payer(&[initialize.clone(), increment], Some(&payer.pubkey()));
+    transaction.sign(&[&payer], recent_blockhash);
+    banks_client.process_transaction(transaction).await.unwrap();
+
+    let account = banks_client.get_account(counter).await.unwrap().unwrap();
+    assert_eq!(account.owner, program_id);
+    assert_eq!(account.data.len(), CounterAccount::LEN);
+    let counter_account = CounterAccount::unpack(&account.data).unwrap();
+    assert_eq!(counter_account.authority, payer.pubkey());
+    assert_eq!(counter_account.counter, CounterValue::I64(10));
+    assert_eq!(counter_account.overflow_policy, OverflowPolicy::Saturate);
+    assert_eq!(counter_account.underflow_policy, UnderflowPolicy::Error);
+    assert_eq!(counter_account.bounds.min, Some(CounterValue::I64(3)));
+
+    let recent_blockhash = banks_client.get_latest_blockhash().await.unwrap();
+    let mut transaction = Transaction::new_with_payer(&[initialize], Some(&payer.pubkey()));
+    transaction.sign(&[&payer], recent_blockhash);
+    assert!(banks_client.process_transaction(transaction).await.is_err());
+}
+
+#[tokio::test]
+async fn test_initialize_with_rejecting_bounds_above_zero() {
+    let program_id = Pubkey::new_unique();
+    let (mut banks_client, payer, recent_blockhash) = program_test(program_id).start().await;
+
+    let seed = b"bounded";
+    let (counter, _) = find_counter_address(&program_id, &payer.pubkey(), seed);
+    let initialize = builder::initialize(
+        &program_id,
+        &payer.pubkey(),
+        InitializeArgs {
+            seed: seed.to_vec(),
+            kind: CounterKind::U64,
+            overflow_policy: OverflowPolicy::Error,
+            underflow_policy: UnderflowPolicy::Error,
+            bounds: Bounds {
+                min: Some(CounterValue::U64(5)),
+                max: None,
+                policy: BoundsPolicy::Reject,
+            },
+        },
+    );
+    let mut transaction = Transaction::new_with_payer(&[initialize], Some(&payer.pubkey()));
+    transaction.sign(&[&payer], recent_blockhash);
+    banks_client.process_transaction(transaction).await.unwrap();
+
+    let account = banks_client.get_account(counter).await.unwrap().unwrap();
+    let counter_account = CounterAccount::unpack(&account.data).unwrap();
+    assert_eq!(counter_account.counter, CounterValue::U64(5));
+
+    let decrement =
+        builder::decrement(&program_id, &counter, &payer.pubkey(), CounterValue::U64(1));
+    let mut transaction = Transaction::new_with_payer(&[decrement], Some(&payer.pubkey()));
+    transaction.sign(&[&payer], recent_blockhash);
+    let error = banks_client
+        .process_transaction(transaction)
+        .await
+        .unwrap_err();
+    assert_eq!(
+        error.unwrap(),
+        TransactionError::InstructionError(
+            0,
+            InstructionError::Custom(CounterError::OutOfBounds as u32)
+        )
+    );
+}
+
+#[tokio::test]
+async fn test_migrate_legacy_counter() {
+    let program_id = Pubkey::new_unique();
+    let counter = Pubkey::new_unique();
+    let mut program_test = program_test(program_id);
+    program_test.add_account(
+        counter,
+        Account {
+            lamports: Rent::default().minimum_balance(4),
+            data: 41u32.to_le_bytes().to_vec(),
+            owner: program_id,
+            ..Account::default()
+        },
+    );
+    // Program data of an upgradeable program whose upgrade authority is `upgrade_authority`.
+    let upgrade_authority = Keypair::new();
+    let (program_data, _) =
+        Pubkey::find_program_address(&[program_id.as_ref()], &bpf_loader_upgradeable::id());
+    let mut data = 3u32.to_le_bytes().to_vec();
+    data.extend_from_slice(&0u64.to_le_bytes());
+    data.push(1);
+    data.extend_from_slice(upgrade_authority.pubkey().as_ref());
+    program_test.add_account(
+        program_data,
+        Account {
+            lamports: Rent::default().minimum_balance(data.len()),
+            data,
+            owner: bpf_loader_upgradeable::id(),
+            ..Account::default()
+        },
+    );
+    let (mut banks_client, payer, recent_blockhash) = program_test.start().await;
+
+    // Whoever pays first cannot claim the counter.
+    let migrate = builder::migrate_legacy(&program_id, &counter, &payer.pubkey(), &payer.pubkey());
+    let mut transaction = Transaction::new_with_payer(&[migrate], Some(&payer.pubkey()));
+    transaction.sign(&[&payer], recent_blockhash);
+    let error = banks_client
+        .process_transaction(transaction)
+        .await
+        .unwrap_err();
+    assert_eq!(
+        error.unwrap(),
+        TransactionError::InstructionError(
+            0,
+            InstructionError::Custom(CounterError::Unauthorized as u32)
+        )
+    );
+
+    let migrate = builder::migrate_legacy(
+        &program_id,
+        &counter,
+        &payer.pubkey(),
+        &upgrade_authority.pubkey(),
+    );
+    let increment = builder::increment(
+        &program_id,
+        &counter,
+        &upgrade_authority.pubkey(),
+        CounterValue::U32(1),
+    );
+
+    let mut transaction = Transaction::new_with_payer(&[migrate, increment], Some(&payer.pubkey()));
+    transaction.sign(&[&payer, &upgrade_authority], recent_blockhash);
+    banks_client.process_transaction(transaction).await.unwrap();
+
+    let account = banks_client.get_account(counter).await.unwrap().unwrap();
+    assert_eq!(account.data.len(), CounterAccount::LEN);
+    assert!(account.lamports >= Rent::default().minimum_balance(CounterAccount::LEN));
+    let counter_account = CounterAccount::unpack(&account.data).unwrap();
+    assert_eq!(counter_account.authority, upgrade_authority.pubkey());
+    assert_eq!(counter_account.counter, CounterValue::U32(42));
+}
+
+#[tokio::test]
+async fn test_migrate_kind_layout_counter() {
+    let program_id = Pubkey::new_unique();
+    let counter = Pubkey::new_unique();
+    let authority = Pubkey::new_unique();
+    let counter_account = CounterAccount {
+        authority,
+        counter: CounterValue::U64(9),
+        overflow_policy: OverflowPolicy::Wrap,
+        underflow_policy: UnderflowPolicy::Error,
+        bounds: Bounds::default(),
+        pending_authority: None,
+    };
+    let mut data = COUNTER_DISCRIMINATOR.to_vec();
+    data.push(KIND_LAYOUT_VERSION);
+    data.extend_from_slice(authority.as_ref());
+    data.extend_from_slice(&borsh::to_vec(&counter_account.counter).unwrap());
+    data.extend_from_slice(&[OverflowPolicy::Wrap as u8, UnderflowPolicy::Error as u8]);
+    let mut program_test = program_test(program_id);
+    program_test.add_account(
+        counter,
+        Account {
+            lamports: Rent::default().minimum_balance(data.len()),
+            data,
+            owner: program_id,
+            ..Account::default()
+        },
+    );
+    let (mut banks_client, payer, recent_blockhash) = program_test.start().await;
+
+    let migrate = builder::migrate(&program_id, &counter, &payer.pubkey());
+    let get = builder::get(&program_id, &counter);
+    let mut transaction =
+        Transaction::new_with_payer(&[migrate.clone(), get.clone()], Some(&payer.pubkey()));
+    transaction.sign(&[&payer], recent_blockhash);
+    banks_client.process_transaction(transaction).await.unwrap();
+
+    let account = banks_client.get_account(counter).await.unwrap().unwrap();
+    assert_eq!(account.data.len(), CounterAccount::LEN);
+    assert_eq!(
+        CounterAccount::unpack(&account.data).unwrap(),
+        counter_account
+    );
+
+    // Already in the current layout.
+    let recent_blockhash = banks_client.get_latest_blockhash().await.unwrap();
+    let mut transaction = Transaction::new_with_payer(&[get, migrate], Some(&payer.pubkey()));
+    transaction.sign(&[&payer], recent_blockhash);
+    assert!(banks_client.process_transaction(transaction).await.is_err());
+}
+
+#[tokio::test]
+async fn test_two_step_authority_transfer() {
+    let program_id = Pubkey::new_unique();
+    let (mut banks_client, payer, recent_blockhash) = program_test(program_id).start().await;
+    let seed = b"handover";
+    let (counter, _) = find_counter_address(&program_id, &payer.pubkey(), seed);
+    let new_authority = Keypair::new();
+
+    let initialize = builder::initialize(
+        &program_id,
+        &payer.pubkey(),
+        InitializeArgs {
+            seed: seed.to_vec(),
+            kind: CounterKind::U32,
+            overflow_policy: OverflowPolicy::Error,
+            underflow_policy: UnderflowPolicy::Saturate,
+            bounds: Bounds::default(),
+        },
+    );
+    let propose = builder::propose_authority(
+        &program_id,
+        &counter,
+        &payer.pubkey(),
+        &new_authority.pubkey(),
+    );
+    let mut transaction =
+        Transaction::new_with_payer(&[initialize, propose], Some(&payer.pubkey()));
+    transaction.sign(&[&payer], recent_blockhash);
+    banks_client.process_transaction(transaction).await.unwrap();
+
+    let account = banks_client.get_account(counter).await.unwrap().unwrap();
+    let counter_account = CounterAccount::unpack(&account.data).unwrap();
+    assert_eq!(counter_account.authority, payer.pubkey());
+    assert_eq!(
+        counter_account.pending_authority,
+        Some(new_authority.pubkey())
+    );
+
+    let accept = builder::accept_authority(&program_id, &counter, &new_authority.pubkey());
+    let increment = builder::increment(
+        &program_id,
+        &counter,
+        &new_authority.pubkey(),
+        CounterValue::U32(1),
+    );
+    let mut transaction = Transaction::new_with_payer(&[accept, increment], Some(&payer.pubkey()));
+    transaction.sign(&[&payer, &new_authority], recent_blockhash);
+    banks_client.process_transaction(transaction).await.unwrap();
+
+    let account = banks_client.get_account(counter).await.unwrap().unwrap();
+    let counter_account = CounterAccount::unpack(&account.data).unwrap();
+    assert_eq!(counter_account.authority, new_authority.pubkey());
+    assert_eq!(counter_account.pending_authority, None);
+    assert_eq!(counter_account.counter, CounterValue::U32(1));
+
+    let reset = builder::reset(&program_id, &counter, &payer.pubkey());
+    let mut transaction = Transaction::new_with_payer(&[reset], Some(&payer.pubkey()));
+    transaction.sign(&[&payer], recent_blockhash);
+    assert!(banks_client.process_transaction(transaction).await.is_err());
+}
+
+#[tokio::test]
+async fn test_multisig_authority() {
+    let program_id = Pubkey::new_unique();
+    let (mut banks_client, payer, recent_blockhash) = program_test(program_id).start().await;
+    let seed = b"multisig";
+    let (counter, _) = find_counter_address(&program_id, &payer.pubkey(), seed);
+    let multisig = Keypair::new();
+    let members = [Keypair::new(), Keypair::new(), Keypair::new()];
+    let rent = banks_client.get_rent().await.unwrap();
+
+    let instructions = [
+        system_instruction::create_account(
+            &payer.pubkey(),
+            &multisig.pubkey(),
+            rent.minimum_balance(Multisig::LEN),
+            Multisig::LEN as u64,
+            &program_id,
+        ),
+        builder::initialize_multisig(
+            &program_id,
+            &multisig.pubkey(),
+            2,
+            members.iter().map(|member| member.pubkey()).collect(),
+        ),
+        builder::initialize(
+            &program_id,
+            &payer.pubkey(),
+            InitializeArgs {
+                seed: seed.to_vec(),
+                kind: CounterKind::U64,
+                overflow_policy: OverflowPolicy::Error,
+                underflow_policy: UnderflowPolicy::Saturate,
+                bounds: Bounds::default(),
+            },
+        ),
+        builder::propose_authority(&program_id, &counter, &payer.pubkey(), &multisig.pubkey()),
+    ];
+    let mut transaction = Transaction::new_with_payer(&instructions, Some(&payer.pubkey()));
+    transaction.sign(&[&payer, &multisig], recent_blockhash);
+    banks_client.process_transaction(transaction).await.unwrap();
+
+    let send = |instruction: Instruction, signers: &[&Keypair]| {
+        let mut signers = signers.to_vec();
+        signers.insert(0, &payer);
+        let mut transaction = Transaction::new_with_payer(&[instruction], Some(&payer.pubkey()));
+        transaction.sign(&signers, recent_blockhash);
+        transaction
+    };
+    let multisig_signed = |instruction: Instruction, signers: &[&Keypair]| {
+        let keys: Vec<Pubkey> = signers.iter().map(|signer| signer.pubkey()).collect();
+        send(
+            builder::with_multisig_signers(instruction, &multisig.pubkey(), &keys),
+            signers,
+        )
+    };
+    let not_enough_signers = TransactionError::InstructionError(
+        0,
+        InstructionError::Custom(CounterError::NotEnoughSigners as u32),
+    );
+
+    let accept = builder::accept_authority(&program_id, &counter, &multisig.pubkey());
+    let error = banks_client
+        .process_transaction(multisig_signed(accept.clone(), &[&members[0]]))
+        .await
+        .unwrap_err();
+    assert_eq!(error.unwrap(), not_enough_signers);
+    banks_client
+        .process_transaction(multisig_signed(accept, &[&members[0], &members[1]]))
+        .await
+        .unwrap();
+
+    // The payer no longer controls the counter on its own.
+    let reset = builder::reset(&program_id, &counter, &payer.pubkey());
+    assert!(banks_client
+        .process_transaction(send(reset, &[]))
+        .await
+        .is_err());
+    // Neither does the key the multisig account was created with.
+    let reset = builder::reset(&program_id, &counter, &multisig.pubkey());
+    let error = banks_client
+        .process_transaction(send(reset, &[&multisig]))
+        .await
+        .unwrap_err();
+    assert_eq!(error.unwrap(), not_enough_signers);
+
+    let update = builder::update(
+        &program_id,
+        &counter,
+        &multisig.pubkey(),
+        CounterValue::U64(12),
+    );
+    let error = banks_client
+        .process_transaction(multisig_signed(update.clone(), &[&members[2]]))
+        .await
+        .unwrap_err();
+    assert_eq!(error.unwrap(), not_enough_signers);
+    banks_client
+        .process_transaction(multisig_signed(update, &[&members[1], &members[2]]))
+        .await
+        .unwrap();
+
+    let account = banks_client.get_account(counter).await.unwrap().unwrap();
+    let counter_account = CounterAccount::unpack(&account.data).unwrap();
+    assert_eq!(counter_account.authority, multisig.pubkey());
+    assert_eq!(counter_account.counter, CounterValue::U64(12));
+}
+
+#[tokio::test]
+async fn test_roles() {
+    let program_id = Pubkey::new_unique();
+    let (mut banks_client, payer, recent_blockhash) = program_test(program_id).start().await;
+    let seed = b"roles";
+    let (counter, _) = find_counter_address(&program_id, &payer.pubkey(), seed);
+    let member = Keypair::new();
+    let admin = Keypair::new();
+
+    let instructions = [
+        builder::initialize(
+            &program_id,
+            &payer.pubkey(),
+            InitializeArgs {
+                seed: seed.to_vec(),
+                kind: CounterKind::U64,
+                overflow_policy: OverflowPolicy::Error,
+                underflow_policy: UnderflowPolicy::Saturate,
+                bounds: Bounds::default(),
+            },
+        ),
+        builder::grant_role(
+            &program_id,
+            &counter,
+            &payer.pubkey(),
+            &payer.pubkey(),
+            &member.pubkey(),
+            Role::Incrementer,
+        ),
+        builder::grant_role(
+            &program_id,
+            &counter,
+            &payer.pubkey(),
+            &payer.pubkey(),
+            &admin.pubkey(),
+            Role::Admin,
+        ),
+    ];
+    let mut transaction = Transaction::new_with_payer(&instructions, Some(&payer.pubkey()));
+    transaction.sign(&[&payer], recent_blockhash);
+    banks_client.process_transaction(transaction).await.unwrap();
+
+    let send = |instruction: Instruction, signer: &Keypair| {
+        let mut transaction = Transaction::new_with_payer(&[instruction], Some(&payer.pubkey()));
+        transaction.sign(&[&payer, signer], recent_blockhash);
+        transaction
+    };
+    let as_member = |instruction: Instruction, signer: &Keypair| {
+        send(
+            builder::with_permissions(instruction, &counter, &signer.pubkey()),
+            signer,
+        )
+    };
+    let unauthorized = TransactionError::InstructionError(
+        0,
+        InstructionError::Custom(CounterError::Unauthorized as u32),
+    );
+    let increment = |amount| {
+        builder::increment(
+            &program_id,
+            &counter,
+            &member.pubkey(),
+            CounterValue::U64(amount),
+        )
+    };
+    let reset = builder::reset(&program_id, &counter, &member.pubkey());
+
+    banks_client
+        .process_transaction(as_member(increment(5), &member))
+        .await
+        .unwrap();
+    // A batch needs the role of every op in it.
+    let batch = builder::batch(
+        &program_id,
+        &counter,
+        &member.pubkey(),
+        vec![Op::Increment(CounterValue::U64(1)), Op::Reset],
+    );
+    let error = banks_client
+        .process_transaction(as_member(batch, &member))
+        .await
+        .unwrap_err();
+    assert_eq!(error.unwrap(), unauthorized);
+    // Without its permissions account the member is just another signer.
+    let error = banks_client
+        .process_transaction(send(increment(6), &member))
+        .await
+        .unwrap_err();
+    assert_eq!(error.unwrap(), unauthorized);
+
+    // Admins grant and revoke every role but their own.
+    let grant_reset = builder::grant_role(
+        &program_id,
+        &counter,
+        &admin.pubkey(),
+        &payer.pubkey(),
+        &member.pubkey(),
+        Role::Resetter,
+    );
+    banks_client
+        .process_transaction(as_member(grant_reset, &admin))
+        .await
+        .unwrap();
+    let grant_admin = builder::grant_role(
+        &program_id,
+        &counter,
+        &admin.pubkey(),
+        &payer.pubkey(),
+        &member.pubkey(),
+        Role::Admin,
+    );
+    let error = banks_client
+        .process_transaction(as_member(grant_admin, &admin))
+        .await
+        .unwrap_err();
+    assert_eq!(error.unwrap(), unauthorized);
+    let revoke_increment = builder::revoke_role(
+        &program_id,
+        &counter,
+        &admin.pubkey(),
+        &member.pubkey(),
+        Role::Incrementer,
+    );
+    banks_client
+        .process_transaction(as_member(revoke_increment, &admin))
+        .await
+        .unwrap();
+
+    let (permissions, _) = find_permissions_address(&program_id, &counter, &member.pubkey());
+    let account = banks_client
+        .get_account(permissions)
+        .await
+        .unwrap()
+        .unwrap();
+    let roles = Permissions::load(&account.data).unwrap().roles();
+    assert!(roles.contains(Role::Resetter));
+    assert!(!roles.contains(Role::Incrementer));
+
+    let error = banks_client
+        .process_transaction(as_member(increment(7), &member))
+        .await
+        .unwrap_err();
+    assert_eq!(error.unwrap(), unauthorized);
+    let account = banks_client.get_account(counter).await.unwrap().unwrap();
+    assert_eq!(
+        CounterAccount::unpack(&account.data).unwrap().counter,
+        CounterValue::U64(5)
+    );
+    banks_client
+        .process_transaction(as_member(reset, &member))
+        .await
+        .unwrap();
+    let account = banks_client.get_account(counter).await.unwrap().unwrap();
+    assert_eq!(
+        CounterAccount::unpack(&account.data).unwrap().counter,
+        CounterValue::U64(0)
+    );
+}
+
+#[tokio::test]
+async fn test_close_permissions() {
+    let program_id = Pubkey::new_unique();
+    let (mut banks_client, payer, recent_blockhash) = program_test(program_id).start().await;
+    let seed = b"close-roles";
+    let (counter, _) = find_counter_address(&program_id, &payer.pubkey(), seed);
+    let member = Keypair::new();
+    let admin = Keypair::new();
+    let resetter = Keypair::new();
+    let initialize = builder::initialize(
+        &program_id,
+        &payer.pubkey(),
+        InitializeArgs {
+            seed: seed.to_vec(),
+            kind: CounterKind::U64,
+            overflow_policy: OverflowPolicy::Error,
+            underflow_policy: UnderflowPolicy::Saturate,
+            bounds: Bounds::default(),
+        },
+    );
+    let grant = |member: &Keypair, role| {
+        builder::grant_role(
+            &program_id,
+            &counter,
+            &payer.pubkey(),
+            &payer.pubkey(),
+            &member.pubkey(),
+            role,
+        )
+    };
+    let instructions = [
+        initialize.clone(),
+        grant(&member, Role::Incrementer),
+        grant(&admin, Role::Admin),
+        grant(&resetter, Role::Resetter),
+    ];
+    let mut transaction = Transaction::new_with_payer(&instructions, Some(&payer.pubkey()));
+    transaction.sign(&[&payer], recent_blockhash);
+    banks_client.process_transaction(transaction).await.unwrap();
+
+    let send = |instruction: Instruction, signer: &Keypair| {
+        let mut transaction = Transaction::new_with_payer(&[instruction], Some(&payer.pubkey()));
+        transaction.sign(&[&payer, signer], recent_blockhash);
+        transaction
+    };
+    let permissions_of =
+        |member: &Keypair| find_permissions_address(&program_id, &counter, &member.pubkey()).0;
+
+    // A member gives up its own roles and gets the rent back.
+    let close_own = builder::close_permissions(
+        &program_id,
+        &counter,
+        &member.pubkey(),
+        &member.pubkey(),
+        &member.pubkey(),
+    );
+    banks_client
+        .process_transaction(send(close_own, &member))
+        .await
+        .unwrap();
+    assert!(banks_client
+        .get_account(permissions_of(&member))
+        .await
+        .unwrap()
+        .is_none());
+    assert_eq!(
+        banks_client.get_balance(member.pubkey()).await.unwrap(),
+        Rent::default().minimum_balance(Permissions::LEN)
+    );
+
+    // Only the authority closes the permissions of someone else.
+    let close_admin = |authority: &Keypair| {
+        builder::close_permissions(
+            &program_id,
+            &counter,
+            &authority.pubkey(),
+            &admin.pubkey(),
+            &authority.pubkey(),
+        )
+    };
+    let error = banks_client
+        .process_transaction(send(close_admin(&resetter), &resetter))
+        .await
+        .unwrap_err();
+    assert_eq!(
+        error.unwrap(),
+        TransactionError::InstructionError(
+            0,
+            InstructionError::Custom(CounterError::Unauthorized as u32)
+        )
+    );
+    let mut transaction =
+        Transaction::new_with_payer(&[close_admin(&payer)], Some(&payer.pubkey()));
+    transaction.sign(&[&payer], recent_blockhash);
+    banks_client.process_transaction(transaction).await.unwrap();
+    assert!(banks_client
+        .get_account(permissions_of(&admin))
+        .await
+        .unwrap()
+        .is_none());
+
+    // Closing the counter takes the permissions passed with it, so a counter created again at
+    // the same address starts without members.
+    let close = builder::with_permissions_to_close(
+        builder::close(&program_id, &counter, &payer.pubkey(), &payer.pubkey()),
+        &counter,
+        &resetter.pubkey(),
+    );
+    let mut transaction = Transaction::new_with_payer(&[close], Some(&payer.pubkey()));
+    transaction.sign(&[&payer], recent_blockhash);
+    banks_client.process_transaction(transaction).await.unwrap();
+    assert!(banks_client
+        .get_account(permissions_of(&resetter))
+        .await
+        .unwrap()
+        .is_none());
+
+    let recent_blockhash = banks_client.get_latest_blockhash().await.unwrap();
+    let mut transaction = Transaction::new_with_payer(&[initialize], Some(&payer.pubkey()));
+    transaction.sign(&[&payer], recent_blockhash);
+    banks_client.process_transaction(transaction).await.unwrap();
+    let reset = builder::with_permissions(
+        builder::reset(&program_id, &counter, &resetter.pubkey()),
+        &counter,
+        &resetter.pubkey(),
+    );
+    let mut transaction = Transaction::new_with_payer(&[reset], Some(&payer.pubkey()));
+    transaction.sign(&[&payer, &resetter], recent_blockhash);
+    let error = banks_client
+        .process_transaction(transaction)
+        .await
+        .unwrap_err();
+    assert_eq!(
+        error.unwrap(),
+        TransactionError::InstructionError(
+            0,
+            InstructionError::Custom(CounterError::Unauthorized as u32)
+        )
+    );
+}
+
+#[tokio::test]
+async fn test_permissions_outlived_by_closed_counter() {
+    let program_id = Pubkey::new_unique();
+    let mut context = program_test(program_id).start_with_context().await;
+    let payer = context.payer.insecure_clone();
+    let seed = b"stale-roles";
+    let (counter, _) = find_counter_address(&program_id, &payer.pubkey(), seed);
+    let member = Keypair::new();
+    let initialize = builder::initialize(
+        &program_id,
+        &payer.pubkey(),
+        InitializeArgs {
+            seed: seed.to_vec(),
+            kind: CounterKind::U64,
+            overflow_policy: OverflowPolicy::Error,
+            underflow_policy: UnderflowPolicy::Saturate,
+            bounds: Bounds::default(),
+        },
+    );
+    let grant = |role| {
+        builder::grant_role(
+            &program_id,
+            &counter,
+            &payer.pubkey(),
+            &payer.pubkey(),
+            &member.pubkey(),
+            role,
+        )
+    };
+    let mut transaction = Transaction::new_with_payer(
+        &[initialize.clone(), grant(Role::Incrementer)],
+        Some(&payer.pubkey()),
+    );
+    transaction.sign(&[&payer], context.last_blockhash);
+    context
+        .banks_client
+        .process_transaction(transaction)
+        .await
+        .unwrap();
+
+    // The counter is closed without the permissions of the member, which stay behind.
+    let close = builder::close(&program_id, &counter, &payer.pubkey(), &payer.pubkey());
+    let mut transaction = Transaction::new_with_payer(&[close], Some(&payer.pubkey()));
+    transaction.sign(&[&payer], context.last_blockhash);
+    context
+        .banks_client
+        .process_transaction(transaction)
+        .await
+        .unwrap();
+    let (permissions, _) = find_permissions_address(&program_id, &counter, &member.pubkey());
+    assert!(context
+        .banks_client
+        .get_account(permissions)
+        .await
+        .unwrap()
+        .is_some());
+
+    // A counter created again at the same address in a later slot does not honour them.
+    context.warp_to_slot(100).unwrap();
+    let recent_blockhash = context.banks_client.get_latest_blockhash().await.unwrap();
+    let mut transaction =
+        Transaction::new_with_payer(&[initialize, grant(Role::Resetter)], Some(&payer.pubkey()));
+    transaction.sign(&[&payer], recent_blockhash);
+    context
+        .banks_client
+        .process_transaction(transaction)
+        .await
+        .unwrap();
+
+    let as_member = |instruction| {
+        let instruction = builder::with_permissions(instruction, &counter, &member.pubkey());
+        let mut transaction = Transaction::new_with_payer(&[instruction], Some(&payer.pubkey()));
+        transaction.sign(&[&payer, &member], recent_blockhash);
+        transaction
+    };
+    let increment = builder::increment(
+        &program_id,
+        &counter,
+        &member.pubkey(),
+        CounterValue::U64(1),
+    );
+    let error = context
+        .banks_client
+        .process_transaction(as_member(increment))
+        .await
+        .unwrap_err();
+    assert_eq!(
+        error.unwrap(),
+        TransactionError::InstructionError(
+            0,
+            InstructionError::Custom(CounterError::Unauthorized as u32)
+        )
+    );
+    let reset = builder::reset(&program_id, &counter, &member.pubkey());
+    context
+        .banks_client
+        .process_transaction(as_member(reset))
+        .await
+        .unwrap();
+
+    let account = context
+        .banks_client
+        .get_account(permissions)
+        .await
+        .unwrap()
+        .unwrap();
+    let roles = Permissions::load(&account.data).unwrap().roles();
+    assert!(roles.contains(Role::Resetter));
+    assert!(!roles.contains(Role::Incrementer));
+}
@@ -0,0 +1,94 @@
+use learn_rust_solana_counter::{
+    cpi, process_instruction, Bounds, CounterAccount, CounterValue, OverflowPolicy, UnderflowPolicy,
+};
+use solana_program_test::{processor, tokio, ProgramTest};
+use solana_sdk::{
+    account::Account,
+    account_info::AccountInfo,
+    entrypoint::ProgramResult,
+    instruction::{AccountMeta, Instruction},
+    program_error::ProgramError,
+    pubkey::Pubkey,
+    rent::Rent,
+    signature::{Keypair, Signer},
+    transaction::Transaction,
+};
+
+/// Increments the counter by 5 through CPI and checks the result with `Get`.
+fn caller_process_instruction(
+    _program_id: &Pubkey,
+    accounts: &[AccountInfo],
+    _instruction_data: &[u8],
+) -> ProgramResult {
+    let [counter_program, counter, authority] = accounts else {
+        return Err(ProgramError::NotEnoughAccountKeys);
+    };
+    cpi::increment(
+        counter_program,
+        counter,
+        authority,
+        CounterValue::U64(5),
+        &[],
+    )?;
+    let counter_account = cpi::get(counter_program, counter)?;
+    if counter_account.counter != CounterValue::U64(6) {
+        return Err(ProgramError::InvalidAccountData);
+    }
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_increment_and_get_through_cpi() {
+    let program_id = Pubkey::new_unique();
+    let caller_id = Pubkey::new_unique();
+    let counter = Pubkey::new_unique();
+    let authority = Keypair::new();
+
+    let mut data = vec![0; CounterAccount::LEN];
+    CounterAccount {
+        authority: authority.pubkey(),
+        counter: CounterValue::U64(1),
+        overflow_policy: OverflowPolicy::Error,
+        underflow_policy: UnderflowPolicy::Saturate,
+        bounds: Bounds::default(),
+        pending_authority: None,
+    }
+    .pack(&mut data)
+    .unwrap();
+
+    let mut program_test = ProgramTest::new(
+        "learn_rust_solana_counter",
+        program_id,
+        processor!(process_instruction),
+    );
+    program_test.add_program("caller", caller_id, processor!(caller_process_instruction));
+    program_test.add_account(
+        counter,
+        Account {
+            lamports: Rent::default().minimum_balance(CounterAccount::LEN),
+            data,
+            owner: program_id,
+            ..Account::default()
+        },
+    );
+    let (mut banks_client, payer, recent_blockhash) = program_test.start().await;
+
+    let instruction = Instruction::new_with_bytes(
+        caller_id,
+        &[],
+        vec![
+            AccountMeta::new_readonly(program_id, false),
+            AccountMeta::new(counter, false),
+            AccountMeta::new_readonly(authority.pubkey(), true),
+        ],
+    );
+    let mut transaction = Transaction::new_with_payer(&[instruction], Some(&payer.pubkey()));
+    transaction.sign(&[&payer, &authority], recent_blockhash);
+    banks_client.process_transaction(transaction).await.unwrap();
+
+    let account = banks_client.get_account(counter).await.unwrap().unwrap();
+    assert_eq!(
+        CounterAccount::unpack(&account.data).unwrap().counter,
+        CounterValue::U64(6)
+    );
+}